@@ -0,0 +1,722 @@
+//! # Thermostat_EEM alarm
+//!
+//! The alarm monitors the input temperatures against their limits (with hysteresis), the fan,
+//! the setpoint deviation, output saturation and TEC resistance drift of the output channels, the
+//! rate of change of the input temperatures, the supply rails and the input sensors.
+//! It is evaluated on the device and its state is published over MQTT as an [AlarmMessage] listing
+//! the active faults. In latching mode a brief excursion keeps the alarm asserted until it is
+//! acknowledged.
+//!
+//! Each alarm condition has a configurable action that constrains the output channels and sets
+//! front panel LEDs while the condition is asserted. Actions are applied on the device
+//! independent of the network connection.
+
+use heapless::{String, Vec};
+use miniconf::{Leaf, Tree};
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{interlock::Constraint, output_channel::State, validate};
+
+/// Miniconf settings for the MQTT alarm.
+/// The alarm publishes an [AlarmMessage] onto its `target` and retained onto
+/// `<prefix>/alarm`. It is asserted if any of the channels is outside its `temperature_limits`
+/// (aka logical OR of all channels) or any of the other enabled conditions is active.
+///
+/// The publishing interval is given by `period`. See also `on_change`.
+///
+/// The alarm is also asserted if the fan is stalled.
+///
+/// By default the alarm is non-latching. If alarm was "true" for a while and the temperatures
+/// come within limits again, alarm will be "false" again. See `latching`.
+#[derive(Clone, Debug, Tree)]
+pub struct Alarm {
+    /// Set the alarm to armed (true) or disarmed (false).
+    /// If the alarm is armed, the device will publish it's alarm state onto the `target`.
+    ///
+    /// # Value
+    /// True to arm, false to disarm.
+    pub armed: Leaf<bool>,
+
+    /// Alarm target.
+    /// The alarm will publish its state (see [AlarmMessage]) onto this mqtt path.
+    /// Full path to the desired target. No wildcards.
+    ///
+    /// # Value
+    /// Any string up to 128 characters.
+    pub target: Leaf<String<128>>,
+
+    /// Alarm period in seconds.
+    /// The alarm is evaluated and its state published with this period.
+    ///
+    /// # Value
+    /// f32
+    pub period: Leaf<f32>,
+
+    /// Publish only on changes.
+    ///
+    /// If true, the alarm state is only published when it changes (asserted state, active
+    /// conditions or acknowledge) instead of every `period`.
+    pub on_change: Leaf<bool>,
+
+    /// Temperature limits for the alarm.
+    ///
+    /// Array of lower and upper limits for the valid temperature range of the alarm.
+    /// The alarm will be asserted if any of the enabled input channels goes below its minimum or above its maximum temperature.
+    /// Unless `latching`, the alarm clears itself once all channels are in their respective limits.
+    ///
+    /// # Path
+    /// `temperature_limits/<adc>/<channel>`
+    /// * `<adc> := [0, 1, 2, 3]` specifies which adc to configure.
+    /// * `<channel>` specifies which channel of an ADC to configure. Only the enabled channels for the specific ADC are available.
+    ///
+    /// # Value
+    /// `[f32, f32]` or `None`
+    pub temperature_limits: [[Option<[Leaf<f32>; 2]>; 4]; 4],
+
+    /// Temperature limit hysteresis.
+    ///
+    /// An input channel alarm clears once the temperature is back within its limits by at
+    /// least the hysteresis.
+    ///
+    /// # Path
+    /// `hysteresis/<adc>/<channel>`
+    ///
+    /// # Value
+    /// Non-negative temperature difference
+    pub hysteresis: [[Leaf<f32>; 4]; 4],
+
+    /// Latching alarm.
+    ///
+    /// If true, the alarm stays asserted after an excursion until it is acknowledged using the
+    /// `acknowledge` command.
+    pub latching: Leaf<bool>,
+
+    /// Setpoint deviation.
+    ///
+    /// Asserted if the loop input of an output channel in closed-loop temperature control
+    /// deviates from its setpoint by more than `threshold` (input units).
+    pub deviation: Check,
+
+    /// Rate of change.
+    ///
+    /// Asserted if the magnitude of the rate of change of an input temperature exceeds
+    /// `threshold` (K/s).
+    pub rate: Check,
+
+    /// Output saturation.
+    ///
+    /// Asserted if the output current of an enabled output channel is within `threshold` (A) of
+    /// its limits.
+    pub saturation: Check,
+
+    /// Supply rails.
+    ///
+    /// Asserted if a supply rail (3.3 V, 5 V, 12 V) deviates from its nominal voltage by more than
+    /// `threshold` (relative).
+    pub supply: Check,
+
+    /// Sensor fault.
+    ///
+    /// Asserted if an input temperature is not finite or its magnitude exceeds `threshold` (°C).
+    pub sensor: Check,
+
+    /// TEC resistance drift.
+    ///
+    /// Asserted if the magnitude of the relative deviation of the estimated TEC resistance of an
+    /// enabled output channel from its reference (see `health`) exceeds `threshold`.
+    pub drift: Check,
+
+    /// Actions taken while an alarm condition is asserted.
+    pub actions: Actions,
+}
+
+impl Default for Alarm {
+    fn default() -> Self {
+        Self {
+            armed: false.into(),
+            target: Default::default(),
+            period: 1.0.into(),
+            on_change: false.into(),
+            temperature_limits: Default::default(),
+            hysteresis: Default::default(),
+            latching: false.into(),
+            deviation: Check::new(1.0, 10.0),
+            rate: Check::new(1.0, 0.0),
+            saturation: Check::new(1e-3, 10.0),
+            supply: Check::new(0.1, 1.0),
+            sensor: Check::new(500.0, 0.0),
+            drift: Check::new(0.2, 10.0),
+            actions: Default::default(),
+        }
+    }
+}
+
+impl Alarm {
+    /// Validate the alarm settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::positive(*self.period, "alarm/period: not positive")?;
+        self.temperature_limits
+            .as_flattened()
+            .iter()
+            .flatten()
+            .try_for_each(|[min, max]| {
+                validate::range([**min, **max], "alarm/temperature_limits: min > max")
+            })?;
+        self.hysteresis
+            .as_flattened()
+            .iter()
+            .try_for_each(|h| validate::non_negative(**h, "alarm/hysteresis: negative"))?;
+        [
+            &self.deviation,
+            &self.rate,
+            &self.saturation,
+            &self.supply,
+            &self.sensor,
+            &self.drift,
+        ]
+        .iter()
+        .try_for_each(|check| check.validate())
+    }
+}
+
+/// Alarm condition with a threshold and a delay.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Check {
+    /// Enable the condition.
+    pub enable: Leaf<bool>,
+
+    /// Threshold. See the individual conditions for the units.
+    pub threshold: Leaf<f32>,
+
+    /// Time in seconds the threshold needs to be exceeded before the condition is asserted.
+    pub delay: Leaf<f32>,
+}
+
+impl Check {
+    /// Validate the condition settings.
+    fn validate(&self) -> validate::Result {
+        validate::non_negative(*self.threshold, "alarm: negative threshold")?;
+        validate::non_negative(*self.delay, "alarm: negative delay")
+    }
+
+    fn new(threshold: f32, delay: f32) -> Self {
+        Self {
+            enable: false.into(),
+            threshold: threshold.into(),
+            delay: delay.into(),
+        }
+    }
+}
+
+/// Runtime state of a delayed [Check].
+#[derive(Copy, Clone, Debug, Default)]
+struct Timer(f32);
+
+impl Timer {
+    /// Update the time the threshold has been exceeded.
+    ///
+    /// # Returns
+    /// True if the condition is asserted.
+    fn update(&mut self, check: &Check, exceeded: bool, dt: f32) -> bool {
+        if *check.enable && exceeded {
+            self.0 += dt;
+            self.0 >= *check.delay
+        } else {
+            self.0 = 0.0;
+            false
+        }
+    }
+}
+
+/// Alarm action.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Action {
+    /// Output channels the action applies to.
+    pub output: Leaf<[bool; 4]>,
+
+    /// Output channel state to force. `None` to keep the configured state.
+    pub state: Leaf<Option<State>>,
+
+    /// Maximum absolute output current in A. `inf` or `NaN` (`null` in JSON) to keep the
+    /// configured limits.
+    pub current_limit: Leaf<f32>,
+
+    /// Front panel LEDs 4 to 7 to turn on.
+    pub led: Leaf<[bool; 4]>,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Self {
+            output: [false; 4].into(),
+            state: None.into(),
+            current_limit: f32::INFINITY.into(),
+            led: [false; 4].into(),
+        }
+    }
+}
+
+/// Actions for each alarm condition.
+#[derive(Copy, Clone, Debug, Default, Tree)]
+pub struct Actions {
+    /// Input temperature outside its limits.
+    pub temperature: Action,
+    /// Fan stall.
+    pub fan: Action,
+    /// Setpoint deviation.
+    pub deviation: Action,
+    /// Input temperature rate of change.
+    pub rate: Action,
+    /// Output saturation.
+    pub saturation: Action,
+    /// Supply rail out of range.
+    pub supply: Action,
+    /// Sensor fault.
+    pub sensor: Action,
+    /// TEC resistance drift.
+    pub drift: Action,
+}
+
+impl Actions {
+    /// The action of an alarm condition.
+    pub fn get(&self, condition: Condition) -> &Action {
+        match condition {
+            Condition::Temperature => &self.temperature,
+            Condition::Fan => &self.fan,
+            Condition::Deviation => &self.deviation,
+            Condition::Rate => &self.rate,
+            Condition::Saturation => &self.saturation,
+            Condition::Supply => &self.supply,
+            Condition::Sensor => &self.sensor,
+            Condition::Drift => &self.drift,
+        }
+    }
+}
+
+/// Alarm conditions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, strum::EnumIter)]
+#[repr(usize)]
+pub enum Condition {
+    /// Input temperature outside its limits.
+    Temperature = 0,
+    /// Fan stall.
+    Fan = 1,
+    /// Setpoint deviation.
+    Deviation = 2,
+    /// Input temperature rate of change.
+    Rate = 3,
+    /// Output saturation.
+    Saturation = 4,
+    /// Supply rail out of range.
+    Supply = 5,
+    /// Sensor fault.
+    Sensor = 6,
+    /// TEC resistance drift.
+    Drift = 7,
+}
+
+const CONDITIONS: usize = 8;
+
+/// Nominal supply rail voltages (3.3 V, 5 V, 12 V).
+const SUPPLY: [f32; 3] = [3.3, 5.0, 12.0];
+
+/// Channel of an alarm condition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Channel {
+    /// Input channel `[<adc>, <channel>]`.
+    Input([u8; 2]),
+    /// Output channel.
+    Output(u8),
+    /// Supply rail (3.3 V, 5 V, 12 V).
+    Supply(u8),
+}
+
+/// Measurements the alarm conditions are evaluated on.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Measurement {
+    /// Input channel temperatures. `None` for disabled channels.
+    pub temperature: [[Option<f32>; 4]; 4],
+    /// The fan is stalled.
+    pub fan_stall: bool,
+    /// Fan speed in RPM.
+    pub fan_rpm: f32,
+    /// Loop input minus setpoint of the output channels in closed-loop temperature control.
+    pub deviation: [Option<f32>; 4],
+    /// Distance of the output current of enabled output channels to their limits in A.
+    pub headroom: [Option<f32>; 4],
+    /// Supply rail voltages (3.3 V, 5 V, 12 V).
+    pub supply: [f32; 3],
+    /// Relative TEC resistance drift of enabled output channels.
+    pub drift: [Option<f32>; 4],
+}
+
+/// First alarm excursion since the last acknowledge.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct FirstFault {
+    /// The alarm condition.
+    pub condition: Condition,
+    /// The channel if applicable.
+    pub channel: Option<Channel>,
+    /// Time since boot in milliseconds.
+    pub time: u32,
+}
+
+/// An active alarm fault.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Fault {
+    /// The alarm condition.
+    pub condition: Condition,
+    /// The channel if applicable.
+    pub channel: Option<Channel>,
+    /// The measured value.
+    pub value: f32,
+    /// The valid range `[min, max]` of the value if applicable.
+    pub limits: Option<[f32; 2]>,
+}
+
+/// Maximum number of faults listed in the [AlarmMessage].
+const FAULTS: usize = 8;
+
+/// Alarm message.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct AlarmMessage<'a> {
+    /// The alarm is asserted.
+    pub asserted: bool,
+    /// The active faults. At most the first 8 are listed.
+    pub faults: &'a [Fault],
+    /// The number of active faults.
+    pub count: u32,
+}
+
+/// Alarm condition status.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ConditionStatus {
+    /// Input temperature outside its limits (see `alarm` for the individual inputs).
+    pub temperature: bool,
+    /// Fan stall.
+    pub fan: bool,
+    /// Setpoint deviation of each output channel.
+    pub deviation: [bool; 4],
+    /// Rate of change of each input channel.
+    pub rate: [[bool; 4]; 4],
+    /// Saturation of each output channel.
+    pub saturation: [bool; 4],
+    /// Supply rails (3.3 V, 5 V, 12 V) out of range.
+    pub supply: [bool; 3],
+    /// Sensor fault of each input channel.
+    pub sensor: [[bool; 4]; 4],
+    /// TEC resistance drift of each output channel.
+    pub drift: [bool; 4],
+}
+
+impl ConditionStatus {
+    /// Active state of each [Condition].
+    fn active(&self) -> [bool; CONDITIONS] {
+        [
+            self.temperature,
+            self.fan,
+            self.deviation.iter().any(|a| *a),
+            self.rate.as_flattened().iter().any(|a| *a),
+            self.saturation.iter().any(|a| *a),
+            self.supply.iter().any(|a| *a),
+            self.sensor.as_flattened().iter().any(|a| *a),
+            self.drift.iter().any(|a| *a),
+        ]
+    }
+}
+
+/// Alarm telemetry.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct AlarmStatus {
+    /// The alarm is asserted.
+    pub asserted: bool,
+    /// Active alarm conditions.
+    pub conditions: ConditionStatus,
+    /// The first excursion since the last acknowledge.
+    pub first: Option<FirstFault>,
+    /// Number of excursions since the last acknowledge.
+    pub excursions: u32,
+}
+
+/// Runtime state of the alarm.
+#[derive(Clone, Debug, Default)]
+pub struct AlarmState {
+    time: Option<u32>,
+    temperature: [[bool; 4]; 4],
+    previous: [[Option<f32>; 4]; 4],
+    rate: [[Timer; 4]; 4],
+    sensor: [[Timer; 4]; 4],
+    deviation: [Timer; 4],
+    saturation: [Timer; 4],
+    supply: [Timer; 3],
+    drift: [Timer; 4],
+    active: [bool; CONDITIONS],
+    latched: [bool; CONDITIONS],
+    asserted: [bool; CONDITIONS],
+    status: AlarmStatus,
+    faults: Vec<Fault, FAULTS>,
+    count: u32,
+    pending: bool,
+}
+
+impl AlarmState {
+    /// Evaluate the alarm.
+    ///
+    /// # Args
+    /// * `settings` - The alarm settings.
+    /// * `measurement` - The measurements to evaluate the conditions on.
+    /// * `time` - Time since boot in milliseconds.
+    ///
+    /// # Returns
+    /// The alarm state of each input channel (`None` for channels without limits) and the
+    /// alarm status.
+    pub fn update(
+        &mut self,
+        settings: &Alarm,
+        measurement: &Measurement,
+        time: u32,
+    ) -> ([[Option<bool>; 4]; 4], AlarmStatus) {
+        let dt = self
+            .time
+            .map_or(0.0, |last| time.wrapping_sub(last) as f32 * 1e-3);
+        let previous = (
+            self.time.replace(time).is_some(),
+            self.status.asserted,
+            self.status.conditions,
+            self.temperature,
+        );
+
+        let mut first = None;
+        let mut excursions = 0;
+        let mut faults = Vec::new();
+        let mut count = 0;
+        let mut register = |fault: Fault, active: &mut bool, now: bool| {
+            if now && !*active {
+                excursions += 1;
+                first.get_or_insert((fault.condition, fault.channel));
+            }
+            if now {
+                count += 1;
+                faults.push(fault).ok();
+            }
+            *active = now;
+        };
+        let conditions = &mut self.status.conditions;
+
+        let mut alarms = [[None; 4]; 4];
+        for (adc, limits) in settings.temperature_limits.iter().enumerate() {
+            for (ch, limits) in limits.iter().enumerate() {
+                let Some(t) = measurement.temperature[adc][ch] else {
+                    self.temperature[adc][ch] = false;
+                    self.previous[adc][ch] = None;
+                    conditions.rate[adc][ch] = false;
+                    conditions.sensor[adc][ch] = false;
+                    continue;
+                };
+                let channel = Some(Channel::Input([adc as u8, ch as u8]));
+
+                if let Some([min, max]) = limits {
+                    let active = &mut self.temperature[adc][ch];
+                    let margin = if *active {
+                        *settings.hysteresis[adc][ch]
+                    } else {
+                        0.0
+                    };
+                    let within = t >= **min + margin && t <= **max - margin;
+                    let fault = Fault {
+                        condition: Condition::Temperature,
+                        channel,
+                        value: t,
+                        limits: Some([**min, **max]),
+                    };
+                    register(fault, active, !within);
+                    alarms[adc][ch] = Some(*active);
+                } else {
+                    self.temperature[adc][ch] = false;
+                }
+
+                let rate = match self.previous[adc][ch].replace(t) {
+                    Some(previous) if dt > 0.0 => (t - previous) / dt,
+                    _ => 0.0,
+                };
+                let threshold = *settings.rate.threshold;
+                let now = self.rate[adc][ch].update(&settings.rate, rate.abs() > threshold, dt);
+                let fault = Fault {
+                    condition: Condition::Rate,
+                    channel,
+                    value: rate,
+                    limits: Some([-threshold, threshold]),
+                };
+                register(fault, &mut conditions.rate[adc][ch], now);
+
+                let threshold = *settings.sensor.threshold;
+                let exceeded = !t.is_finite() || t.abs() > threshold;
+                let now = self.sensor[adc][ch].update(&settings.sensor, exceeded, dt);
+                let fault = Fault {
+                    condition: Condition::Sensor,
+                    channel,
+                    value: t,
+                    limits: Some([-threshold, threshold]),
+                };
+                register(fault, &mut conditions.sensor[adc][ch], now);
+            }
+        }
+        conditions.temperature = self.temperature.as_flattened().iter().any(|a| *a);
+
+        let fault = Fault {
+            condition: Condition::Fan,
+            channel: None,
+            value: measurement.fan_rpm,
+            limits: None,
+        };
+        register(fault, &mut conditions.fan, measurement.fan_stall);
+
+        for (ch, (deviation, headroom)) in measurement
+            .deviation
+            .iter()
+            .zip(measurement.headroom.iter())
+            .enumerate()
+        {
+            let channel = Some(Channel::Output(ch as u8));
+
+            let threshold = *settings.deviation.threshold;
+            let exceeded = deviation.is_some_and(|d| d.abs() > threshold);
+            let now = self.deviation[ch].update(&settings.deviation, exceeded, dt);
+            let fault = Fault {
+                condition: Condition::Deviation,
+                channel,
+                value: deviation.unwrap_or_default(),
+                limits: Some([-threshold, threshold]),
+            };
+            register(fault, &mut conditions.deviation[ch], now);
+
+            let threshold = *settings.saturation.threshold;
+            let exceeded = headroom.is_some_and(|h| h <= threshold);
+            let now = self.saturation[ch].update(&settings.saturation, exceeded, dt);
+            let fault = Fault {
+                condition: Condition::Saturation,
+                channel,
+                value: headroom.unwrap_or_default(),
+                limits: Some([threshold, f32::INFINITY]),
+            };
+            register(fault, &mut conditions.saturation[ch], now);
+        }
+
+        for (ch, drift) in measurement.drift.iter().enumerate() {
+            let threshold = *settings.drift.threshold;
+            let exceeded = drift.is_some_and(|d| !(d.abs() <= threshold));
+            let now = self.drift[ch].update(&settings.drift, exceeded, dt);
+            let fault = Fault {
+                condition: Condition::Drift,
+                channel: Some(Channel::Output(ch as u8)),
+                value: drift.unwrap_or_default(),
+                limits: Some([-threshold, threshold]),
+            };
+            register(fault, &mut conditions.drift[ch], now);
+        }
+
+        for (rail, (voltage, nominal)) in measurement.supply.iter().zip(SUPPLY.iter()).enumerate() {
+            let threshold = *settings.supply.threshold;
+            let exceeded = !((voltage / nominal - 1.0).abs() <= threshold);
+            let now = self.supply[rail].update(&settings.supply, exceeded, dt);
+            let fault = Fault {
+                condition: Condition::Supply,
+                channel: Some(Channel::Supply(rail as u8)),
+                value: *voltage,
+                limits: Some([nominal * (1.0 - threshold), nominal * (1.0 + threshold)]),
+            };
+            register(fault, &mut conditions.supply[rail], now);
+        }
+
+        if let Some((condition, channel)) = first {
+            self.status.excursions += excursions;
+            self.status.first.get_or_insert(FirstFault {
+                condition,
+                channel,
+                time,
+            });
+        }
+        self.active = self.status.conditions.active();
+        for ((active, latched), asserted) in self
+            .active
+            .iter()
+            .zip(self.latched.iter_mut())
+            .zip(self.asserted.iter_mut())
+        {
+            *latched |= *active;
+            *asserted = *active || (*settings.latching && *latched);
+        }
+        self.status.asserted = self.asserted.iter().any(|a| *a);
+        self.faults = faults;
+        self.count = count;
+        self.pending |= previous
+            != (
+                true,
+                self.status.asserted,
+                self.status.conditions,
+                self.temperature,
+            );
+        (alarms, self.status)
+    }
+
+    /// The alarm message of the last evaluation.
+    pub fn message(&self) -> AlarmMessage<'_> {
+        AlarmMessage {
+            asserted: self.status.asserted,
+            faults: &self.faults,
+            count: self.count,
+        }
+    }
+
+    /// Whether the alarm state changed since the last [AlarmState::published].
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Mark the alarm state as published.
+    pub fn published(&mut self) {
+        self.pending = false;
+    }
+
+    /// The combined actions of all asserted alarm conditions.
+    ///
+    /// # Returns
+    /// The output channel constraints and the LEDs 4 to 7 to turn on.
+    pub fn actions(&self, settings: &Alarm) -> ([Constraint; 4], [bool; 4]) {
+        let mut constraints = [Constraint::default(); 4];
+        let mut leds = [false; 4];
+        for condition in Condition::iter().filter(|c| self.asserted[*c as usize]) {
+            let action = settings.actions.get(condition);
+            for (constraint, output) in constraints.iter_mut().zip(action.output.iter()) {
+                if *output {
+                    *constraint = constraint.restrict(Constraint {
+                        state: *action.state,
+                        current_limit: validate::unlimited(*action.current_limit),
+                    });
+                }
+            }
+            for (led, on) in leds.iter_mut().zip(action.led.iter()) {
+                *led |= *on;
+            }
+        }
+        (constraints, leds)
+    }
+
+    /// Acknowledge the alarm.
+    ///
+    /// Clears the latches, the first fault and the excursion count.
+    pub fn acknowledge(&mut self) {
+        self.latched = [false; CONDITIONS];
+        self.asserted = self.active;
+        self.status.asserted = self.asserted.iter().any(|a| *a);
+        self.status.first = None;
+        self.status.excursions = 0;
+        self.pending = true;
+    }
+
+    /// Reset the alarm when it is disarmed.
+    pub fn disarm(&mut self) {
+        *self = Self::default();
+    }
+}
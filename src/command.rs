@@ -0,0 +1,138 @@
+//! # Thermostat_EEM device commands
+//!
+//! Commands act on the runtime state of the device (as opposed to its settings). They are accepted
+//! from the USB terminal as `platform <command>[=<argument>]` and over MQTT on
+//! `<prefix>/command/<command>` with the argument as the payload. Commands are executed by the
+//! application and the response is written to the terminal or published to the MQTT response
+//! topic of the request.
+
+use core::{ops::Range, str::FromStr};
+use heapless::String;
+
+/// Maximum length of a command argument.
+pub const ARGUMENT_SIZE: usize = 64;
+
+/// Maximum length of a command response.
+pub const RESPONSE_SIZE: usize = 512;
+
+/// Command response text.
+pub type Response = String<RESPONSE_SIZE>;
+
+/// Available commands.
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, strum::EnumString, strum::IntoStaticStr, strum::EnumIter,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum Command {
+    /// Acknowledge a watchdog reset or the alarm.
+    Acknowledge,
+    /// Clear the faults of an output channel (argument `0` to `3`) or all channels (no argument).
+    ResetFault,
+    /// Start an identification run on an output channel (argument `0` to `3`) using its
+    /// identification settings.
+    Identify,
+    /// Stop the identification run of an output channel (argument `0` to `3`) or all channels (no
+    /// argument).
+    IdentifyStop,
+    /// Read the event log starting at the event index given as the argument (default `0`).
+    Events,
+    /// Clear the event log.
+    ClearEvents,
+    /// Save the settings that differ from their persisted values to flash.
+    Save,
+    /// List the settings that differ from their persisted values.
+    Unsaved,
+    /// Save the settings as the named profile (argument).
+    ProfileSave,
+    /// Load the named profile (argument).
+    ProfileLoad,
+    /// List the profiles.
+    ProfileList,
+    /// Delete the named profile (argument).
+    ProfileDelete,
+    /// Set the named profile (argument) as the boot default or clear it (no argument).
+    ProfileDefault,
+    /// Begin a settings transaction. Settings writes are staged until `commit` or `abort`.
+    Begin,
+    /// Validate and apply the staged settings.
+    Commit,
+    /// Discard the staged settings.
+    Abort,
+    /// List the staged settings that differ from the active settings.
+    Diff,
+    /// Export the complete configuration as a JSON document.
+    Export,
+    /// Import a configuration document. See [crate::config].
+    Import,
+}
+
+/// Command errors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The command is not known.
+    Unknown,
+    /// The argument is invalid or too long.
+    Argument,
+    /// The response did not fit into the buffer.
+    Truncated,
+    /// Flash storage access failed.
+    Storage,
+    /// No settings transaction is in progress, or one is already in progress for `begin` and
+    /// `import`.
+    Transaction,
+    /// The settings are invalid.
+    Invalid(&'static str),
+    /// The configuration document buffer is in use by another import.
+    Busy,
+}
+
+impl From<core::fmt::Error> for Error {
+    fn from(_: core::fmt::Error) -> Self {
+        Self::Truncated
+    }
+}
+
+/// A command and its argument.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub argument: String<ARGUMENT_SIZE>,
+}
+
+impl Request {
+    /// Construct a request from a command name and a raw argument.
+    pub fn new(command: &str, argument: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            command: command.parse().map_err(|_| Error::Unknown)?,
+            argument: core::str::from_utf8(argument)
+                .ok()
+                .and_then(|a| a.trim().try_into().ok())
+                .ok_or(Error::Argument)?,
+        })
+    }
+
+    /// The output channels given as the argument: one channel (`0` to `3`) or all channels (no
+    /// argument).
+    pub fn channels(&self) -> Result<Range<usize>, Error> {
+        match self.argument.as_str() {
+            "" => Ok(0..4),
+            ch => {
+                let ch: usize = ch.parse().map_err(|_| Error::Argument)?;
+                if ch >= 4 {
+                    return Err(Error::Argument);
+                }
+                Ok(ch..ch + 1)
+            }
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = Error;
+
+    /// Parse a request of the form `<command>[=<argument>]`.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (command, argument) = s.split_once('=').unwrap_or((s, ""));
+        Self::new(command, argument.as_bytes())
+    }
+}
@@ -0,0 +1,288 @@
+//! # Thermostat_EEM configuration export and import
+//!
+//! The complete settings (`/thermostat_eem` and `/net`) are exported as one JSON document and can
+//! be imported from one, e.g. to clone the configuration of a unit to another unit. The document
+//! is an object mapping each settings path to its JSON value on a single line:
+//!
+//! ```json
+//! {"/thermostat_eem/telemetry_period":1.0,"/net/broker":"mqtt",...}
+//! ```
+//!
+//! The MQTT ID `/net/id` is derived from the MAC address by default and identifies the unit. It is
+//! neither exported nor imported. Absent values (e.g. input channels that are not fitted) are not
+//! exported and skipped on import.
+//!
+//! An import is applied to a copy of the settings and validated as a whole. It is rejected if the
+//! document is malformed, contains unknown paths or invalid values. Imported network settings are
+//! saved to flash and take effect after a reboot. The imported `thermostat_eem` settings are
+//! applied and saved using the `save` command. As the network settings can not be staged, imports
+//! are refused during a transaction.
+//!
+//! Over the USB terminal, `platform export` writes the document. `platform import` imports the
+//! document sent as the next line. It is received as raw text bypassing the terminal as it does
+//! not fit the terminal input and its values may contain whitespace. Over MQTT, the response to `<prefix>/command/export` is the document and
+//! `<prefix>/command/import` imports the document given as the payload. A single document buffer
+//! is shared by both: it is held by the USB terminal platform and lent to the MQTT client between
+//! polls of the USB terminal.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+use miniconf::{json, Path, Traversal, TreeDeserializeOwned, TreeKey, TreeSerialize};
+
+use crate::command::Error;
+
+/// Maximum size of a configuration document.
+pub const DOCUMENT_SIZE: usize = 8192;
+
+/// Size of the parts of the document written to the USB terminal at a time.
+pub const CHUNK_SIZE: usize = 512;
+
+/// Configuration document buffer.
+pub type Document = Vec<u8, DOCUMENT_SIZE>;
+
+/// Paths that are neither exported nor imported.
+const EXCLUDED: &[&str] = &["/net/id"];
+
+/// Maximum serialized size of a value.
+const VALUE_SIZE: usize = 256;
+
+/// Incremental export of the configuration document.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Export {
+    /// Index of the next settings node.
+    index: usize,
+    /// Whether a value has been written.
+    written: bool,
+}
+
+impl Export {
+    /// Write the next part of the document.
+    ///
+    /// # Args
+    /// * `settings` - The settings to export.
+    /// * `limit` - The maximum size of the part. At least one value is written.
+    /// * `writer` - The destination of the document.
+    ///
+    /// # Returns
+    /// True if the document is complete.
+    pub fn write<T: TreeKey + TreeSerialize, const Y: usize>(
+        &mut self,
+        settings: &T,
+        limit: usize,
+        writer: &mut impl Write,
+    ) -> Result<bool, Error> {
+        let mut value = [0u8; VALUE_SIZE];
+        let mut size = 0;
+        if self.index == 0 && !self.written {
+            writer.write_str("{")?;
+        }
+        for path in T::nodes::<Path<String<128>, '/'>, Y>().skip(self.index) {
+            let Ok((path, _node)) = path else {
+                self.index += 1;
+                continue;
+            };
+            let len = match json::get_by_key(settings, &path, &mut value) {
+                Ok(len) if !EXCLUDED.contains(&path.as_str()) => len,
+                Ok(_) | Err(miniconf::Error::Traversal(Traversal::Absent(_))) => {
+                    self.index += 1;
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Failed to export `{}`: {e:?}", path.as_str());
+                    return Err(Error::Truncated);
+                }
+            };
+            let separator = if self.written { "," } else { "" };
+            // `"<path>":<value>`
+            let line = separator.len() + path.as_str().len() + 3 + len;
+            if size > 0 && size + line > limit {
+                return Ok(false);
+            }
+            write!(
+                writer,
+                "{separator}\"{}\":{}",
+                path.as_str(),
+                core::str::from_utf8(&value[..len]).map_err(|_| Error::Truncated)?
+            )?;
+            size += line;
+            self.index += 1;
+            self.written = true;
+        }
+        writeln!(writer, "}}")?;
+        Ok(true)
+    }
+}
+
+/// Writer into a byte buffer.
+pub struct Cursor<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// The number of bytes written.
+    pub fn position(&self) -> usize {
+        self.len
+    }
+}
+
+impl Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.buffer
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Split the JSON value at the start of `data` from the remainder of the document.
+///
+/// The value ends at the first `,` or `}` outside of strings, arrays and objects.
+fn split_value(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut depth = 0usize;
+    let mut string = false;
+    let mut escape = false;
+    for (i, c) in data.iter().enumerate() {
+        if string {
+            match c {
+                _ if escape => escape = false,
+                b'\\' => escape = true,
+                b'"' => string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            b'"' => string = true,
+            b'[' | b'{' => depth += 1,
+            b']' | b'}' if depth > 0 => depth -= 1,
+            b',' | b'}' => {
+                let (value, rest) = data.split_at(i);
+                return Some((value.trim_ascii_end(), rest));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Import a configuration document into the settings.
+///
+/// Values are validated individually while they are imported. As intermediate states may be
+/// rejected, the caller validates the result as a whole.
+///
+/// # Returns
+/// The number of imported values.
+pub fn import<T: TreeKey + TreeDeserializeOwned, const Y: usize>(
+    settings: &mut T,
+    document: &[u8],
+) -> Result<usize, Error> {
+    let mut data = document
+        .trim_ascii()
+        .strip_prefix(b"{")
+        .ok_or(Error::Argument)?;
+    let mut count = 0;
+    loop {
+        data = data.trim_ascii_start();
+        if let Some(rest) = data.strip_prefix(b"}") {
+            if !rest.trim_ascii().is_empty() {
+                return Err(Error::Argument);
+            }
+            return Ok(count);
+        }
+        let rest = data.strip_prefix(b"\"").ok_or(Error::Argument)?;
+        let end = rest
+            .iter()
+            .position(|c| *c == b'"')
+            .ok_or(Error::Argument)?;
+        let key = core::str::from_utf8(&rest[..end]).map_err(|_| Error::Argument)?;
+        let rest = rest[end + 1..]
+            .trim_ascii_start()
+            .strip_prefix(b":")
+            .ok_or(Error::Argument)?;
+        let (value, rest) = split_value(rest.trim_ascii_start()).ok_or(Error::Argument)?;
+        data = rest.strip_prefix(b",").unwrap_or(rest);
+
+        if EXCLUDED.contains(&key) {
+            log::info!("Ignoring `{key}` on import");
+            continue;
+        }
+        match json::set_by_key(settings, &Path::<_, '/'>(key), value) {
+            // Rejected values are kept. The result is validated as a whole.
+            Ok(_) | Err(miniconf::Error::Traversal(Traversal::Invalid(..))) => count += 1,
+            Err(miniconf::Error::Traversal(Traversal::Absent(_))) => {
+                log::warn!("Skipping absent `{key}` on import");
+            }
+            Err(miniconf::Error::Traversal(e)) => {
+                log::warn!("Unknown `{key}` on import: {e:?}");
+                return Err(Error::Invalid("import: unknown path"));
+            }
+            Err(e) => {
+                log::warn!("Failed to import `{key}`: {e:?}");
+                return Err(Error::Invalid("import: invalid value"));
+            }
+        }
+    }
+}
+
+/// Writer comparing the written text with a document.
+struct Compare<'a> {
+    document: &'a [u8],
+    len: usize,
+    equal: bool,
+}
+
+impl Write for Compare<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.equal &= self.document.get(self.len..end) == Some(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Export settings, import the document and check that the imported settings export the same
+/// document.
+///
+/// Non-finite values are exported as `null` and imported as `NaN`. This checks that such values
+/// can be imported again. The caller validates the imported settings as a whole.
+///
+/// # Args
+/// * `settings` - The settings to export.
+/// * `imported` - The settings to import into.
+/// * `document` - The document buffer. It is cleared afterwards.
+pub fn round_trip<T: TreeKey + TreeSerialize + TreeDeserializeOwned, const Y: usize>(
+    settings: &T,
+    imported: &mut T,
+    document: &mut Document,
+) -> Result<(), Error> {
+    document.resize_default(DOCUMENT_SIZE).ok();
+    let mut cursor = Cursor::new(&mut document[..]);
+    let result = Export::default().write::<_, Y>(settings, usize::MAX, &mut cursor);
+    let len = cursor.position();
+    document.truncate(len);
+    let result = result
+        .and_then(|_| import::<_, Y>(imported, &document[..]))
+        .and_then(|_| {
+            let mut compare = Compare {
+                document: &document[..],
+                len: 0,
+                equal: true,
+            };
+            Export::default().write::<_, Y>(&*imported, usize::MAX, &mut compare)?;
+            if compare.equal && compare.len == document.len() {
+                Ok(())
+            } else {
+                Err(Error::Invalid("import: round trip mismatch"))
+            }
+        });
+    document.clear();
+    result
+}
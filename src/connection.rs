@@ -0,0 +1,143 @@
+//! # Thermostat_EEM connection-loss policy
+//!
+//! The Ethernet link and the MQTT broker connection are monitored. If the connection is lost for
+//! longer than the timeout, the configured policy is applied to the output channels until the
+//! connection is restored. The policy only applies once the connection has been established after
+//! boot. On reconnection a retained status message is published.
+
+use miniconf::{Leaf, Tree};
+use serde::{Deserialize, Serialize};
+
+use crate::validate;
+
+/// Connection-loss policy.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Policy {
+    /// Continue operation with the current settings.
+    #[default]
+    Continue,
+    /// Hold the outputs.
+    Hold,
+    /// Regulate to the safe setpoints. Only affects output channels in temperature control.
+    Safe,
+    /// Turn the outputs off.
+    Off,
+}
+
+/// Connection-loss policy settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Connection {
+    /// Policy applied while the connection is lost.
+    pub policy: Leaf<Policy>,
+
+    /// Time in seconds without connection before the policy is applied.
+    pub timeout: Leaf<f32>,
+
+    /// Setpoint of each output channel for the `Safe` policy.
+    ///
+    /// Units: input
+    pub setpoint: Leaf<[f32; 4]>,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            policy: Policy::Continue.into(),
+            timeout: 10.0.into(),
+            setpoint: [25.0; 4].into(),
+        }
+    }
+}
+
+impl Connection {
+    /// Validate the connection-loss policy settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::non_negative(*self.timeout, "connection/timeout: negative")?;
+        self.setpoint
+            .iter()
+            .try_for_each(|s| validate::finite(*s, "connection/setpoint: not finite"))
+    }
+}
+
+/// Connection status.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct ConnectionStatus {
+    /// The Ethernet link is up and the MQTT broker is connected.
+    pub connected: bool,
+    /// Number of connection losses since boot.
+    pub losses: u32,
+    /// Duration of the last connection loss in seconds.
+    pub downtime: f32,
+    /// The policy applied during the last connection loss. `None` if the timeout did not elapse.
+    pub applied: Option<Policy>,
+}
+
+/// Runtime state of the connection monitor.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ConnectionMonitor {
+    status: ConnectionStatus,
+    booted: bool,
+    pending: bool,
+}
+
+impl ConnectionMonitor {
+    /// Update the connection monitor.
+    ///
+    /// # Args
+    /// * `settings` - Connection-loss policy settings.
+    /// * `connected` - The Ethernet link is up and the MQTT broker is connected.
+    /// * `period` - Update period in seconds.
+    ///
+    /// # Returns
+    /// The policy to apply to the output channels.
+    pub fn update(&mut self, settings: &Connection, connected: bool, period: f32) -> Policy {
+        let status = &mut self.status;
+        if connected {
+            if !status.connected {
+                if self.booted {
+                    log::info!("Connection restored after {} s", status.downtime);
+                }
+                status.connected = true;
+                self.booted = true;
+                self.pending = true;
+            }
+            return Policy::Continue;
+        }
+
+        if status.connected {
+            log::warn!("Connection lost");
+            status.connected = false;
+            status.losses += 1;
+            status.downtime = 0.0;
+            status.applied = None;
+        }
+        if !self.booted {
+            // Not connected yet after boot.
+            return Policy::Continue;
+        }
+        status.downtime += period;
+        if status.downtime < *settings.timeout {
+            return Policy::Continue;
+        }
+        if status.applied.is_none() && *settings.policy != Policy::Continue {
+            log::error!("Connection lost: applying {:?} policy", *settings.policy);
+        }
+        status.applied = Some(*settings.policy);
+        *settings.policy
+    }
+
+    /// The connection status.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    /// Whether the connection status needs to be published.
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Mark the connection status as published.
+    pub fn published(&mut self) {
+        self.pending = false;
+    }
+}
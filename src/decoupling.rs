@@ -0,0 +1,120 @@
+//! # Thermostat_EEM output decoupling
+//!
+//! Thermally coupled zones lead to cross-talk between the individual output channel loops.
+//! An optional decoupling matrix maps the four loop outputs onto the four output currents.
+
+use miniconf::{Leaf, Tree};
+
+/// Output decoupling matrix settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Decoupling {
+    /// Enable (true) or bypass (false) the decoupling matrix.
+    pub enable: Leaf<bool>,
+
+    /// Decoupling matrix.
+    ///
+    /// `matrix[<i>][<j>]` is the contribution of the loop output of channel `<j>`
+    /// to the output current of channel `<i>`.
+    ///
+    /// # Value
+    /// `[[f32; 4]; 4]`, defaults to identity.
+    pub matrix: Leaf<[[f32; 4]; 4]>,
+}
+
+impl Default for Decoupling {
+    fn default() -> Self {
+        let mut matrix = [[0.0; 4]; 4];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Self {
+            enable: false.into(),
+            matrix: matrix.into(),
+        }
+    }
+}
+
+impl Decoupling {
+    /// Apply the decoupling matrix to the loop outputs and return the output currents.
+    ///
+    /// If any output current exceeds its limits, the loop output vector is scaled down as a whole
+    /// until all currents are within their limits. This keeps the direction of the decoupled
+    /// output and prevents a saturated channel from disturbing the others. The loop outputs are
+    /// scaled in place so that the reduction can be fed back to the loops (anti-windup).
+    ///
+    /// # Args
+    /// * `output` - Loop outputs. Scaled in place on saturation.
+    /// * `limits` - `[min, max]` output current limits for each channel.
+    pub fn update(&self, output: &mut [f64; 4], limits: &[[f64; 2]; 4]) -> [f64; 4] {
+        let mut current = [0.0; 4];
+        for (c, row) in current.iter_mut().zip(self.matrix.iter()) {
+            *c = row
+                .iter()
+                .zip(output.iter())
+                .map(|(m, y)| *m as f64 * y)
+                .sum();
+        }
+        let scale = current
+            .iter()
+            .zip(limits.iter())
+            .fold(1.0f64, |scale, (c, [min, max])| {
+                if c > max {
+                    scale.min(max / c)
+                } else if c < min {
+                    scale.min(min / c)
+                } else {
+                    scale
+                }
+            })
+            .max(0.0);
+        if scale < 1.0 {
+            for y in output.iter_mut() {
+                *y *= scale;
+            }
+        }
+        // Limits that do not include zero can not be met by scaling alone.
+        for (c, [min, max]) in current.iter_mut().zip(limits.iter()) {
+            *c = (*c * scale).max(*min).min(*max);
+        }
+        current
+    }
+
+    /// The loop output changes that produce the given output current changes.
+    ///
+    /// This maps output current constraints back to the loops (anti-windup).
+    ///
+    /// # Returns
+    /// `None` if the matrix is singular.
+    pub fn solve(&self, current: [f64; 4]) -> Option<[f64; 4]> {
+        // Gauss-Jordan elimination with partial pivoting on the augmented matrix.
+        let mut a: [[f64; 5]; 4] = core::array::from_fn(|i| {
+            core::array::from_fn(|j| {
+                if j < 4 {
+                    self.matrix[i][j] as f64
+                } else {
+                    current[i]
+                }
+            })
+        });
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))
+                .unwrap_or(col);
+            if a[pivot][col] == 0.0 {
+                return None;
+            }
+            a.swap(col, pivot);
+            let p = a[col];
+            for (row, r) in a.iter_mut().enumerate() {
+                if row != col {
+                    let f = r[col] / p[col];
+                    for (x, y) in r.iter_mut().zip(p.iter()).skip(col) {
+                        *x -= f * y;
+                    }
+                }
+            }
+        }
+        let output = core::array::from_fn(|i| a[i][4] / a[i][i]);
+        output.iter().all(|y: &f64| y.is_finite()).then_some(output)
+    }
+}
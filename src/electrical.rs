@@ -0,0 +1,139 @@
+//! # Thermostat_EEM electrical output control
+//!
+//! A slow outer loop uses the measured TEC voltage and current to limit the electrical output
+//! power and to operate an output channel at constant voltage or constant power.
+
+use miniconf::{Leaf, Tree};
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use crate::validate;
+
+/// Output channel control mode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Temperature control using the PID/Biquad.
+    #[default]
+    Temperature,
+    /// Constant output voltage.
+    Voltage,
+    /// Constant output power. Assumes a positive output current (e.g. resistive heaters).
+    Power,
+}
+
+/// Electrical output control settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Electrical {
+    /// Control mode.
+    pub mode: Leaf<Mode>,
+
+    /// Output voltage (V) or output power (W) setpoint in the `Voltage` and `Power` modes.
+    pub setpoint: Leaf<f32>,
+
+    /// Integral gain of the outer loop.
+    ///
+    /// Units: A/(V s) in `Voltage` mode, A/(W s) in `Power` mode.
+    pub gain: Leaf<f32>,
+
+    /// Maximum electrical output power in W.
+    ///
+    /// The output current is limited using the measured TEC resistance.
+    ///
+    /// # Value
+    /// Positive, `inf` or `NaN` (`null` in JSON) to disable.
+    pub power_limit: Leaf<f32>,
+}
+
+impl Default for Electrical {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Temperature.into(),
+            setpoint: 0.0.into(),
+            gain: 0.1.into(),
+            power_limit: f32::INFINITY.into(),
+        }
+    }
+}
+
+impl Electrical {
+    /// Validate the electrical output control settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::finite(*self.setpoint, "electrical/setpoint: not finite")?;
+        validate::finite(*self.gain, "electrical/gain: not finite")?;
+        validate::limit(*self.power_limit, "electrical/power_limit: negative")
+    }
+
+    /// The output current command of the outer loop if the channel is not in `Temperature` mode.
+    pub fn command(&self, command: f64) -> Option<f64> {
+        (*self.mode != Mode::Temperature).then_some(command)
+    }
+}
+
+/// Runtime state of the outer loop.
+#[derive(Copy, Clone, Debug)]
+pub struct OuterLoop {
+    /// TEC resistance estimate in Ohm. NaN if unknown.
+    resistance: f32,
+    /// Output current command in A.
+    command: f32,
+}
+
+impl Default for OuterLoop {
+    fn default() -> Self {
+        Self {
+            resistance: f32::NAN,
+            command: 0.0,
+        }
+    }
+}
+
+impl OuterLoop {
+    /// Minimum absolute current to update the resistance estimate.
+    const MIN_CURRENT: f32 = 0.01;
+
+    /// Update the outer loop with new measurements.
+    ///
+    /// # Args
+    /// * `settings` - Electrical settings of the channel.
+    /// * `voltage` - Measured output voltage in V.
+    /// * `current` - Measured output current in A.
+    /// * `limits` - `[min, max]` output current limits in A.
+    /// * `on` - The channel is `On`. Otherwise the outer loop tracks the measured output.
+    /// * `period` - Update period in seconds.
+    ///
+    /// # Returns
+    /// The output current command and the power derived current limit.
+    pub fn update(
+        &mut self,
+        settings: &Electrical,
+        voltage: f32,
+        current: f32,
+        limits: [f32; 2],
+        on: bool,
+        period: f32,
+    ) -> (f64, f32) {
+        if current.abs() > Self::MIN_CURRENT {
+            self.resistance = (voltage / current).abs();
+        }
+        let current_limit = if settings.power_limit.is_finite() && self.resistance > 0.0 {
+            (*settings.power_limit / self.resistance).sqrt()
+        } else {
+            f32::INFINITY
+        };
+        let error = match *settings.mode {
+            Mode::Temperature => 0.0,
+            Mode::Voltage => *settings.setpoint - voltage,
+            Mode::Power => *settings.setpoint - voltage * current,
+        };
+        let [min, max] = limits;
+        self.command = (self.command + *settings.gain * error * period)
+            .max(min.max(-current_limit))
+            .min(max.min(current_limit));
+        if *settings.mode == Mode::Temperature || !on {
+            // Track the measured output for a bumpless transfer and to keep the integrator from
+            // winding up while the channel is not `On`.
+            self.command = current;
+        }
+        (self.command as _, current_limit)
+    }
+}
@@ -0,0 +1,139 @@
+//! # Thermostat_EEM event log
+//!
+//! Events (boot, alarms, faults, settings changes, connection changes, etc.) are timestamped and
+//! queued in RAM by the tasks that observe them. The `usb` task, which owns the flash, persists
+//! them into a ring buffer in a reserved flash region using `sequential-storage`. Once the region
+//! is full, the oldest events are overwritten.
+//!
+//! The log is read using the `events[=<start>]` command and cleared using the `clear_events`
+//! command, both from the USB terminal and over MQTT.
+
+use core::fmt::Write;
+use embassy_futures::block_on;
+use heapless::{Deque, String};
+use sequential_storage::{cache::NoCache, queue};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{Error, Response},
+    fault::Code,
+    hardware::{flash::Flash, platform::ResetCause},
+};
+
+/// Logged events.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// Device boot with the reset cause.
+    Boot(ResetCause),
+    /// Settings changed over MQTT or the USB terminal.
+    Settings,
+    /// Alarm asserted (`true`) or cleared (`false`).
+    Alarm(bool),
+    /// Fault raised on an output channel.
+    Fault { channel: u8, code: Code },
+    /// Faults cleared on an output channel.
+    FaultReset(u8),
+    /// Board overtemperature shutdown.
+    Overtemperature,
+    /// Supply rail under-voltage.
+    Undervoltage,
+    /// Network connection restored (`true`) or lost (`false`).
+    Connection(bool),
+    /// Watchdog reset and alarm acknowledged.
+    Acknowledge,
+}
+
+/// A timestamped event.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// Time since boot in milliseconds.
+    pub time: u32,
+    /// The event.
+    pub event: Event,
+}
+
+/// Maximum serialized size of a [Record].
+const RECORD_SIZE: usize = 32;
+
+/// Event log.
+#[derive(Clone, Debug, Default)]
+pub struct EventLog {
+    pending: Deque<Record, 16>,
+}
+
+impl EventLog {
+    /// Queue an event to be persisted.
+    ///
+    /// # Args
+    /// * `time` - Time since boot in milliseconds.
+    /// * `event` - The event.
+    pub fn push(&mut self, time: u32, event: Event) {
+        log::info!("Event: {event:?}");
+        if self.pending.is_full() {
+            log::warn!("Event queue overflow");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(Record { time, event }).ok();
+    }
+
+    /// Whether events are waiting to be persisted.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Persist the queued events to flash.
+    pub fn persist(&mut self, storage: &mut Flash) {
+        let range = storage.log_range();
+        let mut buf = [0u8; RECORD_SIZE];
+        while let Some(record) = self.pending.pop_front() {
+            let Ok(data) = postcard::to_slice(&record, &mut buf) else {
+                continue;
+            };
+            if let Err(e) = block_on(queue::push(
+                storage,
+                range.clone(),
+                &mut NoCache::new(),
+                data,
+                true,
+            )) {
+                log::error!("Failed to persist event: {e:?}");
+            }
+        }
+    }
+}
+
+/// Read the persisted events.
+///
+/// The events are written as JSON lines starting at the index `start` (oldest first) until the
+/// response is full. A final `next=<index>` line indicates that more events are available.
+pub fn read(storage: &mut Flash, start: usize, response: &mut Response) -> Result<(), Error> {
+    let range = storage.log_range();
+    let mut cache = NoCache::new();
+    let mut iter = block_on(queue::iter(storage, range, &mut cache)).map_err(|_| Error::Storage)?;
+    let mut buf = [0u8; RECORD_SIZE];
+    let mut index = 0;
+    while let Some(entry) = block_on(iter.next(&mut buf)).map_err(|_| Error::Storage)? {
+        if index >= start {
+            let Ok(record) = postcard::from_bytes::<Record>(&entry) else {
+                index += 1;
+                continue;
+            };
+            let line: String<96> =
+                serde_json_core::to_string(&record).map_err(|_| Error::Truncated)?;
+            // Keep room for the `next=<index>` line.
+            if response.len() + line.len() + 18 > response.capacity() {
+                write!(response, "next={index}")?;
+                return Ok(());
+            }
+            writeln!(response, "{line}")?;
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Erase the persisted events.
+pub fn clear(storage: &mut Flash) -> Result<(), Error> {
+    let range = storage.log_range();
+    block_on(sequential_storage::erase_all(storage, range)).map_err(|_| Error::Storage)
+}
@@ -0,0 +1,116 @@
+//! # Thermostat_EEM output channel faults
+//!
+//! Each output channel carries a fault state. When a fault is raised, the channel is turned off
+//! using the [Interlock] and the fault is latched until it is cleared using the `reset_fault`
+//! command. Faults that persist are raised again.
+
+use serde::{Deserialize, Serialize, Serializer};
+use strum::IntoEnumIterator;
+
+use crate::{
+    hardware::OutputChannelIdx,
+    interlock::{Constraint, Interlock, Source},
+    output_channel::State,
+};
+
+/// Output channel fault codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, strum::EnumIter)]
+#[repr(u8)]
+pub enum Code {
+    /// Loop input is not finite in closed-loop operation.
+    Sensor = 0,
+    /// Output current outside of the DAC range.
+    Dac = 1,
+    /// Voltage or current limit outside of the PWM range.
+    Limit = 2,
+    /// PID/Biquad build failure.
+    Pid = 3,
+    /// Board overtemperature.
+    Overtemperature = 4,
+    /// TEC open or short circuit.
+    Tec = 5,
+}
+
+/// Set of fault codes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Codes(u8);
+
+impl Codes {
+    /// Whether the set contains a code.
+    pub fn contains(&self, code: Code) -> bool {
+        self.0 & (1 << code as u8) != 0
+    }
+
+    fn insert(&mut self, code: Code) {
+        self.0 |= 1 << code as u8;
+    }
+}
+
+impl Serialize for Codes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(Code::iter().filter(|c| self.contains(*c)))
+    }
+}
+
+/// Fault state of an output channel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaultState {
+    /// Latched fault codes.
+    pub codes: Codes,
+    /// The first fault code since the last reset.
+    pub first: Option<Code>,
+}
+
+impl FaultState {
+    /// Whether the channel is faulted.
+    pub fn faulted(&self) -> bool {
+        self.first.is_some()
+    }
+}
+
+/// Fault state of all output channels.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Faults([FaultState; 4]);
+
+impl Faults {
+    /// Raise a fault on an output channel.
+    ///
+    /// # Returns
+    /// True if the effective interlock state of the channel changed.
+    pub fn raise(&mut self, interlock: &mut Interlock, ch: OutputChannelIdx, code: Code) -> bool {
+        let fault = &mut self.0[ch as usize];
+        if fault.codes.contains(code) {
+            return false;
+        }
+        log::error!("Output channel {ch:?} fault: {code:?}");
+        fault.codes.insert(code);
+        fault.first.get_or_insert(code);
+        interlock.set(
+            Source::Fault,
+            ch,
+            Constraint {
+                state: Some(State::Off),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Clear the faults of an output channel.
+    ///
+    /// # Returns
+    /// True if the effective interlock state of the channel changed.
+    pub fn reset(&mut self, interlock: &mut Interlock, ch: OutputChannelIdx) -> bool {
+        self.0[ch as usize] = FaultState::default();
+        interlock.set(Source::Fault, ch, Constraint::default())
+    }
+
+    /// The fault state of an output channel.
+    pub fn get(&self, ch: OutputChannelIdx) -> FaultState {
+        self.0[ch as usize]
+    }
+
+    /// The fault state of all output channels.
+    pub fn all(&self) -> [FaultState; 4] {
+        self.0
+    }
+}
@@ -74,7 +74,10 @@ impl AdcPhy {
 
 #[derive(Debug)]
 pub enum Error {
+    /// Unexpected ADC ID.
     Ident,
+    /// Readout state machine in an unexpected state.
+    State,
 }
 
 /// All pins for all ADCs.
@@ -562,14 +565,16 @@ impl sm::StateMachine<Adc> {
     ///
     /// This routine is called every time the currently selected ADC on Thermostat reports that it has data ready
     /// to be read out by pulling the dout line low. It then reads out the ADC data via SPI.
-    pub fn handle_interrupt(&mut self) -> (AdcPhy, usize, AdcCode) {
-        if let sm::States::Selected(phy) = *self.state() {
-            let (code, status) = self.context_mut().read_data();
-            let adc_ch = status.channel().value() as _;
-            self.process_event(sm::Events::Read).unwrap();
-            (phy, adc_ch, code)
-        } else {
-            panic!("Unexpected State")
-        }
+    pub fn handle_interrupt(&mut self) -> Result<(AdcPhy, usize, AdcCode), Error> {
+        let sm::States::Selected(phy) = *self.state() else {
+            // Acknowledge the interrupt to not get stuck in it.
+            self.context_mut().rdyn.clear_interrupt_pending_bit();
+            return Err(Error::State);
+        };
+        let (code, status) = self.context_mut().read_data();
+        let adc_ch = status.channel().value() as _;
+        self.process_event(sm::Events::Read)
+            .map_err(|_| Error::State)?;
+        Ok((phy, adc_ch, code))
     }
 }
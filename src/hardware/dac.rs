@@ -30,10 +30,13 @@ const SPI_CLOCK: MegaHertz = MegaHertz::MHz(8);
 pub const R_SENSE: f32 = 0.05; // TEC current sense resistor
 pub const VREF_TEC: f32 = 1.5; // TEC driver reference voltage
 
-/// DAC value out of bounds error.
-#[derive(Debug)]
+/// DAC errors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Error {
+    /// DAC value out of bounds.
     Bounds,
+    /// SPI transfer failure.
+    Spi,
 }
 
 /// A type representing a DAC sample.
@@ -118,7 +121,7 @@ impl Dac {
 
         // default to zero current
         for i in OutputChannelIdx::iter() {
-            dac.set(i, (0.0).try_into().unwrap());
+            dac.set(i, (0.0).try_into().unwrap()).unwrap();
         }
         dac
     }
@@ -128,11 +131,12 @@ impl Dac {
     /// # Args
     /// * `ch` - Thermostat output channel
     /// * `dac_code` - dac output code to transfer
-    pub fn set(&mut self, ch: OutputChannelIdx, dac_code: DacCode) {
+    pub fn set(&mut self, ch: OutputChannelIdx, dac_code: DacCode) -> Result<(), Error> {
         self.pins.sync[ch as usize].set_low();
         // 24 bit write. 4 MSB are zero and 2 LSB are ignored for a 18 bit DAC output.
         let buf = u32::from(dac_code).to_be_bytes();
-        self.spi.write(&buf[1..]).unwrap();
+        let result = self.spi.write(&buf[1..]).map_err(|_| Error::Spi);
         self.pins.sync[ch as usize].set_high();
+        result
     }
 }
@@ -4,8 +4,7 @@ use super::hal::{
     prelude::*,
     pwm::Pwm,
     pwm::{ComplementaryDisabled, C2},
-    // rcc::ResetEnable,
-    rcc::{rec, CoreClocks},
+    rcc::{rec, CoreClocks, ResetEnable},
     stm32::{TIM2, TIM8},
     time::KiloHertz,
 };
@@ -21,10 +20,22 @@ pub enum Error {
 
 pub struct Fan {
     pwm: Pwm<TIM8, C2, ComplementaryDisabled>,
-    // tacho:
+    tacho: TIM2,
+    _tacho_pin: PB10<Alternate<1>>,
+    // Timer count of the last tacho edge
+    last: Option<u32>,
+    // Timer counts between the last two tacho edges
+    period: u32,
 }
 
 impl Fan {
+    /// Tacho timer count frequency in Hz.
+    const TACHO_CLOCK: u32 = 10_000;
+    /// Tacho pulses per fan revolution.
+    const PULSES_PER_REVOLUTION: f32 = 2.0;
+    /// Time without tacho edges after which the fan is considered stopped (timer counts).
+    const TACHO_TIMEOUT: u32 = Self::TACHO_CLOCK;
+
     pub fn new(
         clocks: &CoreClocks,
         tim_rec: (rec::Tim2, rec::Tim8),
@@ -36,24 +47,76 @@ impl Fan {
         pwm.set_duty(0);
         pwm.enable();
 
-        // const F_TACHO: Hertz = Hertz(2);
-        // let mut _tacho = tim.0.pwm(pins.tacho, F_TACHO, tim_rec.0, clocks);
-        // let tim2 = unsafe { &*TIM2::PTR };
-        // tim2.ccer.modify(|_, w| w.cc3e())
+        // The tacho is measured using input capture of TIM2 CH3 on a free running 32 bit counter.
+        tim_rec.0.enable().reset();
+        let tacho = tim.0;
+        tacho.psc.write(|w| {
+            w.psc()
+                .bits((clocks.timx_ker_ck().raw() / Self::TACHO_CLOCK - 1) as u16)
+        });
+        // Capture TI3 with the maximum input filter to reject PWM crosstalk.
+        tacho
+            .ccmr2_input()
+            .write(|w| unsafe { w.cc3s().bits(0b01).ic3f().bits(0b1111) });
+        tacho.ccer.write(|w| w.cc3e().set_bit());
+        tacho.dier.write(|w| w.cc3ie().set_bit());
+        tacho.egr.write(|w| w.ug().set_bit());
+        tacho.sr.reset();
+        tacho.cr1.write(|w| w.cen().set_bit());
 
         Fan {
             pwm,
-            // tacho,
+            tacho,
+            _tacho_pin: pins.tacho,
+            last: None,
+            period: 0,
         }
     }
 
     pub fn set_duty(&mut self, duty: f32) -> Result<i32, Error> {
         let max = self.pwm.get_max_duty() as i32;
         let code = (duty * max as f32) as i32;
-        if !(0..max).contains(&code) {
+        if !(0..=max).contains(&code) {
             return Err(Error::Bounds);
         }
         self.pwm.set_duty(code as u16);
         Ok(code)
     }
+
+    /// Handle the tacho input capture interrupt.
+    pub fn handle_interrupt(&mut self) {
+        let sr = self.tacho.sr.read();
+        if sr.cc3if().bit_is_clear() {
+            return;
+        }
+        // Reading the capture clears the interrupt flag.
+        let capture = self.tacho.ccr3.read().bits();
+        if sr.cc3of().bit_is_set() {
+            // Edges were missed. Resynchronize.
+            self.tacho.sr.modify(|_, w| w.cc3of().clear_bit());
+        } else if let Some(last) = self.last {
+            self.period = capture.wrapping_sub(last);
+        }
+        self.last = Some(capture);
+    }
+
+    /// Fan speed in revolutions per minute.
+    pub fn rpm(&mut self) -> f32 {
+        let now = self.tacho.cnt.read().bits();
+        match self.last {
+            Some(last) if now.wrapping_sub(last) < Self::TACHO_TIMEOUT => {
+                if self.period == 0 {
+                    0.0
+                } else {
+                    60.0 * Self::TACHO_CLOCK as f32
+                        / (self.period as f32 * Self::PULSES_PER_REVOLUTION)
+                }
+            }
+            _ => {
+                self.last = None;
+                self.period = 0;
+                0.0
+            }
+        }
+    }
 }
@@ -6,8 +6,20 @@ use stm32h7xx_hal::flash::Error as FlashError;
 pub struct Flash(pub LockedFlashBank);
 
 impl Flash {
+    /// Size of the flash region reserved for the event log at the end of the bank.
+    const LOG_SIZE: u32 = 2 * stm32h7xx_hal::flash::UnlockedFlashBank::ERASE_SIZE as u32;
+
+    /// Flash range used for the settings.
+    ///
+    /// Settings persisted by earlier firmware using the entire bank are moved into this range by
+    /// [crate::settings::upgrade_layout].
     pub fn range(&self) -> core::ops::Range<u32> {
-        0..(self.0.len() as u32)
+        0..(self.0.len() as u32 - Self::LOG_SIZE)
+    }
+
+    /// Flash range reserved for the event log.
+    pub fn log_range(&self) -> core::ops::Range<u32> {
+        (self.0.len() as u32 - Self::LOG_SIZE)..(self.0.len() as u32)
     }
 }
 
@@ -1,9 +1,28 @@
-use serde::Serialize;
+use serde::{Serialize, Serializer};
+
+use rtic_monotonics::Monotonic;
+
+use super::{platform::ResetCause, Systick};
 
 mod build_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
+/// Time since boot in seconds.
+pub fn uptime() -> u32 {
+    Systick::now().ticks() / super::MONOTONIC_FREQUENCY
+}
+
+/// Uptime in seconds, read when serialized.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Uptime;
+
+impl Serialize for Uptime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(uptime())
+    }
+}
+
 #[derive(Serialize)]
 pub struct ApplicationMetadata {
     pub firmware_version: &'static str,
@@ -13,6 +32,9 @@ pub struct ApplicationMetadata {
     pub features: &'static str,
     pub panic_info: &'static str,
     pub hardware_version: u8,
+    pub reset_cause: ResetCause,
+    pub boot_count: u32,
+    pub uptime: Uptime,
 }
 
 impl ApplicationMetadata {
@@ -23,10 +45,16 @@ impl ApplicationMetadata {
     ///
     /// # Args
     /// * `hardware_version` - The hardware version detected.
+    /// * `reset_cause` - The cause of the last reset.
+    /// * `boot_count` - The persistent boot counter.
     ///
     /// # Returns
     /// A reference to the global metadata.
-    pub fn new(version: u8) -> &'static ApplicationMetadata {
+    pub fn new(
+        version: u8,
+        reset_cause: ResetCause,
+        boot_count: u32,
+    ) -> &'static ApplicationMetadata {
         cortex_m::singleton!(: ApplicationMetadata = ApplicationMetadata {
             firmware_version: build_info::GIT_VERSION.unwrap_or("Unspecified"),
             rust_version: build_info::RUSTC_VERSION,
@@ -35,6 +63,9 @@ impl ApplicationMetadata {
             features: build_info::FEATURES_STR,
             hardware_version: version,
             panic_info: panic_persist::get_panic_message_utf8().unwrap_or("None"),
+            reset_cause,
+            boot_count,
+            uptime: Uptime,
         })
         .unwrap()
     }
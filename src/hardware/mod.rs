@@ -61,5 +61,8 @@ pub type SerialTerminal<C, const Y: usize> =
 
 pub type UsbBus = stm32h7xx_hal::usb_hs::UsbBus<stm32h7xx_hal::usb_hs::USB2>;
 
+// Type alias for the board temperature sensor.
+pub type Lm75 = lm75::Lm75<hal::i2c::I2c<hal::stm32::I2C1>, lm75::ic::Lm75>;
+
 // Type alias for the USB device.
 pub type UsbDevice = usb_device::device::UsbDevice<'static, UsbBus>;
@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+use stm32h7xx_hal::stm32::RCC;
+
 /// Flag used to indicate that a reboot to DFU is requested.
 const DFU_REBOOT_FLAG: u32 = 0xDEAD_BEEF;
 
@@ -68,3 +71,50 @@ pub fn execute_system_bootloader() {
         cortex_m::asm::bootload(system_memory_address);
     }
 }
+
+/// Cause of the last reset as reported by the RCC reset status register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResetCause {
+    /// Power-on reset.
+    PowerOn,
+    /// Brown-out reset.
+    BrownOut,
+    /// External reset pin.
+    Pin,
+    /// Software reset (e.g. `reboot`).
+    Software,
+    /// Independent watchdog reset.
+    Watchdog,
+    /// Window watchdog reset.
+    WindowWatchdog,
+    /// Illegal low-power mode entry.
+    LowPower,
+    /// No reset flag set.
+    Unknown,
+}
+
+/// Read the cause of the last reset.
+///
+/// # Note
+/// This must be called before the reset flags are cleared. Multiple flags are set for most reset
+/// sources (e.g. a watchdog reset also asserts the reset pin). The most specific one is reported.
+pub fn reset_cause(rcc: &RCC) -> ResetCause {
+    let rsr = rcc.rsr.read();
+    if rsr.lpwrrstf().bit_is_set() {
+        ResetCause::LowPower
+    } else if rsr.wwdg1rstf().bit_is_set() {
+        ResetCause::WindowWatchdog
+    } else if rsr.iwdg1rstf().bit_is_set() {
+        ResetCause::Watchdog
+    } else if rsr.sftrstf().bit_is_set() {
+        ResetCause::Software
+    } else if rsr.porrstf().bit_is_set() {
+        ResetCause::PowerOn
+    } else if rsr.borrstf().bit_is_set() {
+        ResetCause::BrownOut
+    } else if rsr.pinrstf().bit_is_set() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    }
+}
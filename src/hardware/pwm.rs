@@ -29,8 +29,9 @@ pub enum Limit {
 }
 
 /// PWM value out of bounds error.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Error {
+    /// Limit value out of bounds.
     Bounds,
 }
 
@@ -11,6 +11,7 @@ use super::hal::{
     self as hal,
     ethernet::{self, PHY},
     gpio::{GpioExt, Speed},
+    independent_watchdog::IndependentWatchdog,
     prelude::*,
 };
 
@@ -22,6 +23,7 @@ use super::{
     fan::{Fan, FanPins},
     gpio::Gpio,
     metadata::ApplicationMetadata,
+    platform::ResetCause,
     pwm::{Pwm, PwmPins},
     EthernetPhy, NetworkStack, Systick,
 };
@@ -112,6 +114,9 @@ pub struct ThermostatDevices<C: serial_settings::Settings + 'static, const Y: us
     pub usb: super::UsbDevice,
     pub metadata: &'static ApplicationMetadata,
     pub settings: C,
+    pub watchdog: IndependentWatchdog,
+    pub reset_cause: ResetCause,
+    pub lm75: Option<super::Lm75>,
 }
 
 #[link_section = ".sram3.eth"]
@@ -185,7 +190,8 @@ where
 
     device.RCC.d3ccipr.modify(|_, w| w.adcsel().per());
 
-    // Clear reset flags.
+    // Record and clear reset flags.
+    let reset_cause = platform::reset_cause(&device.RCC);
     device.RCC.rsr.write(|w| w.rmvf().set_bit());
 
     let rcc = device.RCC.constrain();
@@ -210,6 +216,7 @@ where
     core.SCB.enable_icache();
 
     info!("--- Starting hardware setup");
+    info!("Reset cause: {:?}", reset_cause);
 
     let mut delay = delay::AsmDelay::new(ccdr.clocks.c_ck().to_Hz());
 
@@ -449,10 +456,11 @@ where
     };
 
     let mut eui48 = [0; 6];
-    if i2c.write_read(0x50, &[0xFA], &mut eui48).is_err() {
+    let lm75 = if i2c.write_read(0x50, &[0xFA], &mut eui48).is_err() {
         // wrong ESD protection https://github.com/sinara-hw/Thermostat_EEM/issues/51
         log::warn!("I2C failure, using default MAC");
         eui48 = [0x02, 0x00, 0x00, 0x00, 0x00, 0xd3];
+        None
     } else {
         let mut lm75 = lm75::Lm75::new(i2c, lm75::Address::default());
         log::info!("LM75 Temperature: {}°C", lm75.read_temperature().unwrap());
@@ -461,19 +469,28 @@ where
         } else {
             log::warn!("AFE EUI48 read failure.");
         }
-    }
+        Some(lm75)
+    };
     let mac_addr = smoltcp::wire::EthernetAddress(eui48);
     log::info!("EUI48: {}", mac_addr);
 
-    let (flash, mut settings) = {
+    let (flash, mut settings, boot_count, migration) = {
         let mut flash = {
             let (_, flash_bank2) = device.FLASH.split();
             super::flash::Flash(flash_bank2.unwrap())
         };
 
+        crate::settings::upgrade_layout(&mut flash);
         let mut settings = C::new(crate::NetSettings::new(mac_addr));
-        crate::settings::SerialSettingsPlatform::<_, Y>::load(&mut settings, &mut flash);
-        (flash, settings)
+        let mut migration = crate::migration::migrate::<C, Y>(&mut flash);
+        crate::settings::SerialSettingsPlatform::<_, Y>::load(
+            &mut settings,
+            &mut flash,
+            &mut migration,
+        );
+        let boot_count = crate::settings::count_boot(&mut flash);
+        info!("Boot count: {}", boot_count);
+        (flash, settings, boot_count, migration)
     };
 
     info!("Setup Ethernet");
@@ -673,17 +690,27 @@ where
         (usb_device, serial)
     };
 
-    let metadata = ApplicationMetadata::new(gpio.hwrev());
+    let metadata = ApplicationMetadata::new(gpio.hwrev(), reset_cause, boot_count);
 
     let usb_terminal = {
         let input_buffer = cortex_m::singleton!(: [u8; 128] = [0u8; 128]).unwrap();
         let serialize_buffer = cortex_m::singleton!(: [u8; 512] = [0u8; 512]).unwrap();
+        // The configuration document buffer shared by the USB terminal and MQTT.
+        let document = cortex_m::singleton!(
+            : crate::config::Document = crate::config::Document::new()
+        )
+        .unwrap();
 
         serial_settings::Runner::new(
             crate::settings::SerialSettingsPlatform {
                 interface: serial_settings::BestEffortInterface::new(usb_serial),
                 storage: flash,
                 metadata,
+                pending: None,
+                migration,
+                import: Some(document),
+                export: None,
+                raw: false,
                 _settings_marker: core::marker::PhantomData,
             },
             input_buffer,
@@ -693,6 +720,9 @@ where
         .unwrap()
     };
 
+    // The watchdog is started by the application once it is ready to feed it.
+    let watchdog = IndependentWatchdog::new(device.IWDG);
+
     info!("--- Hardware setup done");
 
     ThermostatDevices {
@@ -709,5 +739,8 @@ where
         settings,
         usb: usb_device,
         metadata,
+        watchdog,
+        reset_cause,
+        lm75,
     }
 }
@@ -0,0 +1,207 @@
+//! # Thermostat_EEM TEC health monitoring
+//!
+//! The TEC resistance and Seebeck voltage are estimated by an exponentially weighted linear
+//! regression of the measured output voltage on the output current: `V = R I + V_s`.
+//! Resistance drift, open and short circuits are flagged. Open and short circuits raise an output
+//! channel fault, the drift is an alarm condition (see [crate::alarm::Alarm]).
+
+use miniconf::{Leaf, Tree};
+use num_traits::Float;
+use serde::Serialize;
+
+use crate::validate;
+
+/// TEC health monitoring settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Health {
+    /// Nominal TEC resistance in Ohm.
+    ///
+    /// # Value
+    /// Positive. `NaN` to use the first estimate after boot as the reference.
+    pub resistance: Leaf<f32>,
+
+    /// Maximum relative resistance deviation from the reference before flagging a drift.
+    pub tolerance: Leaf<f32>,
+
+    /// Resistance above which the TEC is considered an open circuit (Ohm).
+    pub open: Leaf<f32>,
+
+    /// Resistance below which the TEC is considered a short circuit (Ohm).
+    pub short: Leaf<f32>,
+
+    /// Time constant of the regression in seconds.
+    pub time_constant: Leaf<f32>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            resistance: f32::NAN.into(),
+            tolerance: 0.2.into(),
+            open: 100.0.into(),
+            short: 0.1.into(),
+            time_constant: 60.0.into(),
+        }
+    }
+}
+
+/// TEC fault conditions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Condition {
+    /// Open circuit: no current despite output voltage or resistance above `open`.
+    Open,
+    /// Short circuit: resistance below `short`.
+    Short,
+    /// Resistance deviates from the reference by more than `tolerance`.
+    Drift,
+}
+
+/// TEC health telemetry.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct TecHealth {
+    /// Estimated TEC resistance in Ohm.
+    resistance: f32,
+    /// Estimated Seebeck voltage in V.
+    seebeck_voltage: f32,
+    /// Relative resistance deviation from the reference.
+    drift: f32,
+    /// Active fault condition.
+    alarm: Option<Condition>,
+}
+
+impl Health {
+    /// Validate the TEC health monitoring settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::non_negative(*self.tolerance, "health/tolerance: negative")?;
+        validate::range([*self.short, *self.open], "health: short > open")?;
+        validate::positive(*self.time_constant, "health/time_constant: not positive")?;
+        if self.resistance.is_nan() || *self.resistance > 0.0 {
+            Ok(())
+        } else {
+            Err("health/resistance: not positive")
+        }
+    }
+}
+
+impl TecHealth {
+    /// Whether an open or short circuit is detected.
+    pub fn fault(&self) -> bool {
+        matches!(self.alarm, Some(Condition::Open | Condition::Short))
+    }
+
+    /// Relative resistance deviation from the reference.
+    pub fn drift(&self) -> f32 {
+        self.drift
+    }
+}
+
+/// Runtime state of the TEC health estimator.
+#[derive(Copy, Clone, Debug)]
+pub struct Estimator {
+    // Exponentially weighted sums
+    w: f32,
+    i: f32,
+    v: f32,
+    ii: f32,
+    iv: f32,
+    resistance: f32,
+    seebeck_voltage: f32,
+    reference: f32,
+    alarm: Option<Condition>,
+}
+
+impl Default for Estimator {
+    fn default() -> Self {
+        Self {
+            w: 0.0,
+            i: 0.0,
+            v: 0.0,
+            ii: 0.0,
+            iv: 0.0,
+            resistance: f32::NAN,
+            seebeck_voltage: f32::NAN,
+            reference: f32::NAN,
+            alarm: None,
+        }
+    }
+}
+
+impl Estimator {
+    /// Minimum absolute current for a conducting TEC in A.
+    const MIN_CURRENT: f32 = 0.01;
+    /// Minimum absolute voltage to detect an open circuit in V.
+    const MIN_VOLTAGE: f32 = 0.5;
+    /// Minimum current variance for a resistance estimate in A².
+    const MIN_VARIANCE: f32 = 1e-4;
+
+    /// Update the estimator with new measurements.
+    ///
+    /// # Args
+    /// * `settings` - Health settings of the channel.
+    /// * `voltage` - Measured output voltage in V.
+    /// * `current` - Measured output current in A.
+    /// * `period` - Update period in seconds.
+    ///
+    /// # Returns
+    /// The health telemetry.
+    pub fn update(
+        &mut self,
+        settings: &Health,
+        voltage: f32,
+        current: f32,
+        period: f32,
+    ) -> TecHealth {
+        let decay = (-period / *settings.time_constant).exp();
+        self.w = self.w * decay + 1.0;
+        self.i = self.i * decay + current;
+        self.v = self.v * decay + voltage;
+        self.ii = self.ii * decay + current * current;
+        self.iv = self.iv * decay + current * voltage;
+
+        let det = self.w * self.ii - self.i * self.i;
+        if det > Self::MIN_VARIANCE * self.w * self.w {
+            self.resistance = (self.w * self.iv - self.i * self.v) / det;
+            self.seebeck_voltage = (self.v - self.resistance * self.i) / self.w;
+        } else if current.abs() > Self::MIN_CURRENT {
+            // Without current modulation assume the Seebeck voltage is unchanged.
+            let vs = if self.seebeck_voltage.is_finite() {
+                self.seebeck_voltage
+            } else {
+                0.0
+            };
+            self.resistance = (voltage - vs) / current;
+        }
+
+        self.reference = if settings.resistance.is_finite() {
+            *settings.resistance
+        } else if self.reference.is_finite() {
+            self.reference
+        } else {
+            self.resistance
+        };
+        let drift = self.resistance / self.reference - 1.0;
+
+        let alarm = if (current.abs() < Self::MIN_CURRENT && voltage.abs() > Self::MIN_VOLTAGE)
+            || self.resistance > *settings.open
+        {
+            Some(Condition::Open)
+        } else if current.abs() > Self::MIN_CURRENT && self.resistance < *settings.short {
+            Some(Condition::Short)
+        } else if drift.abs() > *settings.tolerance {
+            Some(Condition::Drift)
+        } else {
+            None
+        };
+        if alarm != self.alarm {
+            log::warn!("TEC health condition: {:?}", alarm);
+            self.alarm = alarm;
+        }
+
+        TecHealth {
+            resistance: self.resistance,
+            seebeck_voltage: self.seebeck_voltage,
+            drift,
+            alarm,
+        }
+    }
+}
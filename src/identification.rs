@@ -0,0 +1,284 @@
+//! # Thermostat_EEM system identification
+//!
+//! Excitation signals (step, PRBS, chirp) are injected on top of the output channel current,
+//! either with the loop closed or with the loop output held (open-loop). The loop input response
+//! is streamed at the full rate. Optionally a first-order-plus-dead-time (FOPDT) model is fitted
+//! to a step response on the device.
+//!
+//! Runs are started and stopped explicitly using the `identify` and `identify_stop` commands.
+//! Persisted or loaded settings never start a run.
+
+use miniconf::{Leaf, Tree};
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use crate::{output_channel::SAMPLE_PERIOD, validate};
+
+/// Number of (decimated) response samples recorded for the FOPDT fit.
+const FIT_LENGTH: usize = 256;
+
+/// Excitation signal type.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Excitation {
+    /// No excitation. Identification disabled.
+    #[default]
+    None,
+    /// Step of `amplitude` for the duration of the run.
+    Step,
+    /// Pseudo-random binary sequence of `+-amplitude`.
+    Prbs,
+    /// Linear frequency sweep (chirp) with `amplitude`.
+    Chirp,
+}
+
+/// Identification settings.
+///
+/// A run is started using the `identify` command. It uses the settings at the start and ends
+/// after `duration` or when stopped using the `identify_stop` command.
+#[derive(Copy, Clone, Debug, PartialEq, Tree)]
+pub struct Identification {
+    /// Excitation signal type.
+    pub excitation: Leaf<Excitation>,
+
+    /// Excitation amplitude.
+    ///
+    /// Units: output (A)
+    pub amplitude: Leaf<f32>,
+
+    /// Duration of the run in seconds.
+    pub duration: Leaf<f32>,
+
+    /// Hold the loop output during the run (true) or keep the loop closed (false).
+    pub open_loop: Leaf<bool>,
+
+    /// PRBS bit period in seconds.
+    pub prbs_period: Leaf<f32>,
+
+    /// Chirp start and stop frequencies in Hz.
+    pub chirp: Leaf<[f32; 2]>,
+
+    /// Fit a FOPDT model to the response at the end of a `Step` run.
+    pub fit: Leaf<bool>,
+}
+
+impl Identification {
+    /// Validate the identification settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::finite(*self.amplitude, "identification/amplitude: not finite")?;
+        validate::non_negative(*self.duration, "identification/duration: negative")?;
+        validate::positive(
+            *self.prbs_period,
+            "identification/prbs_period: not positive",
+        )?;
+        validate::non_negative(self.chirp[0], "identification/chirp: negative")?;
+        validate::range(*self.chirp, "identification/chirp: start > stop")
+    }
+}
+
+impl Default for Identification {
+    fn default() -> Self {
+        Self {
+            excitation: Excitation::None.into(),
+            amplitude: 0.1.into(),
+            duration: 60.0.into(),
+            open_loop: true.into(),
+            prbs_period: 1.0.into(),
+            chirp: [0.01, 1.0].into(),
+            fit: true.into(),
+        }
+    }
+}
+
+/// First-order-plus-dead-time model.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Fopdt {
+    /// Static gain.
+    ///
+    /// Units: input/output
+    gain: f32,
+    /// Time constant in seconds.
+    time_constant: f32,
+    /// Dead time in seconds.
+    dead_time: f32,
+}
+
+impl Fopdt {
+    /// Fit a FOPDT model to a step response using the two-point (28.3 % and 63.2 %) method.
+    ///
+    /// # Args
+    /// * `response` - Equidistant response samples starting at the step.
+    /// * `period` - Sample period in seconds.
+    /// * `amplitude` - Step amplitude.
+    fn fit(response: &[f32], period: f32, amplitude: f32) -> Option<Self> {
+        if response.len() < 8 {
+            return None;
+        }
+        let start = response[0];
+        let tail = &response[response.len() - response.len() / 8..];
+        let end = tail.iter().sum::<f32>() / tail.len() as f32;
+        let delta = end - start;
+        if delta == 0.0 || amplitude == 0.0 {
+            return None;
+        }
+        let crossing = |level: f32| {
+            response
+                .iter()
+                .position(|y| (y - start) / delta >= level)
+                .map(|i| i as f32 * period)
+        };
+        let t28 = crossing(0.283)?;
+        let t63 = crossing(0.632)?;
+        let time_constant = 1.5 * (t63 - t28);
+        Some(Self {
+            gain: delta / amplitude,
+            time_constant,
+            dead_time: (t63 - time_constant).max(0.0),
+        })
+    }
+}
+
+/// Requested change of the identification run of an output channel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Trigger {
+    /// No change.
+    #[default]
+    None,
+    /// Start a run, restarting a run in progress.
+    Start,
+    /// Stop a run in progress.
+    Stop,
+}
+
+/// Runtime state of an identification run.
+#[derive(Copy, Clone, Debug)]
+pub struct Identifier {
+    settings: Identification,
+    active: bool,
+    sample: u32,
+    lfsr: u16,
+    level: f32,
+    phase: f32,
+    decimation: u32,
+    response: [f32; FIT_LENGTH],
+    len: usize,
+    model: Option<Fopdt>,
+}
+
+impl Default for Identifier {
+    fn default() -> Self {
+        Self {
+            settings: Default::default(),
+            active: false,
+            sample: 0,
+            lfsr: 0xace1,
+            level: 1.0,
+            phase: 0.0,
+            decimation: 1,
+            response: [0.0; FIT_LENGTH],
+            len: 0,
+            model: None,
+        }
+    }
+}
+
+impl Identifier {
+    fn start(&mut self, settings: &Identification) {
+        let samples = *settings.duration / SAMPLE_PERIOD as f32;
+        *self = Self {
+            settings: *settings,
+            active: *settings.excitation != Excitation::None,
+            decimation: ((samples / FIT_LENGTH as f32).ceil() as u32).max(1),
+            ..Default::default()
+        };
+        if self.active {
+            log::info!("Identification started: {:?}", *settings.excitation);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.active = false;
+        if *self.settings.excitation == Excitation::Step && *self.settings.fit {
+            self.model = Fopdt::fit(
+                &self.response[..self.len],
+                self.decimation as f32 * SAMPLE_PERIOD as f32,
+                *self.settings.amplitude,
+            );
+        }
+        log::info!("Identification finished: {:?}", self.model);
+    }
+
+    /// Advance the identification run by one sample.
+    ///
+    /// # Args
+    /// * `settings` - Identification settings. They are used when a run starts.
+    /// * `trigger` - Requested start or stop of a run.
+    /// * `input` - Loop input (weighted temperature) of the output channel.
+    ///
+    /// # Returns
+    /// The excitation to add to the output current.
+    pub fn update(&mut self, settings: &Identification, trigger: Trigger, input: f64) -> f64 {
+        match trigger {
+            Trigger::None => {}
+            Trigger::Start => self.start(settings),
+            Trigger::Stop => {
+                if self.active {
+                    self.active = false;
+                    log::info!("Identification stopped");
+                }
+            }
+        }
+        if !self.active {
+            return 0.0;
+        }
+        let period = SAMPLE_PERIOD as f32;
+        let t = self.sample as f32 * period;
+        if t >= *self.settings.duration {
+            self.finish();
+            return 0.0;
+        }
+        let excitation = match *self.settings.excitation {
+            Excitation::None => 0.0,
+            Excitation::Step => 1.0,
+            Excitation::Prbs => {
+                let bit = ((*self.settings.prbs_period / period) as u32).max(1);
+                if self.sample % bit == 0 {
+                    // 16 bit maximum length Fibonacci LFSR, taps 16, 14, 13, 11
+                    let l = self.lfsr;
+                    let b = (l ^ (l >> 2) ^ (l >> 3) ^ (l >> 5)) & 1;
+                    self.lfsr = (l >> 1) | (b << 15);
+                    self.level = if b != 0 { 1.0 } else { -1.0 };
+                }
+                self.level
+            }
+            Excitation::Chirp => {
+                let [f0, f1] = *self.settings.chirp;
+                let f = f0 + (f1 - f0) * t / *self.settings.duration;
+                let e = self.phase.sin();
+                self.phase =
+                    (self.phase + core::f32::consts::TAU * f * period) % core::f32::consts::TAU;
+                e
+            }
+        } * *self.settings.amplitude;
+        if self.sample % self.decimation == 0 && self.len < FIT_LENGTH {
+            self.response[self.len] = input as _;
+            self.len += 1;
+        }
+        self.sample += 1;
+        excitation as _
+    }
+
+    /// An identification run is in progress.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The loop output is to be held.
+    pub fn open_loop(&self) -> bool {
+        self.active && *self.settings.open_loop
+    }
+
+    /// The FOPDT model fitted to the last step response.
+    pub fn model(&self) -> Option<Fopdt> {
+        self.model
+    }
+}
@@ -0,0 +1,104 @@
+//! # Thermostat_EEM output interlock
+//!
+//! Supervisory functions constrain the output channels at runtime in addition to their settings.
+//! Each source maintains its own constraint for each channel and the most restrictive combination
+//! of all sources is applied to the output.
+
+use serde::Serialize;
+
+use crate::{hardware::OutputChannelIdx, output_channel::State};
+
+/// Source of a runtime output constraint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[repr(usize)]
+pub enum Source {
+    /// Electrical output power limit.
+    Power = 0,
+    /// Safe state after a watchdog reset.
+    Watchdog = 1,
+    /// Board thermal protection.
+    Overtemperature = 2,
+    /// Alarm actions.
+    Alarm = 3,
+    /// Supply power budget and under-voltage shedding.
+    Supply = 4,
+    /// Connection-loss policy.
+    Connection = 5,
+    /// Output channel faults.
+    Fault = 6,
+}
+
+const SOURCES: usize = 7;
+
+/// Runtime constraint of an output channel.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct Constraint {
+    /// Forced output channel state. `None` keeps the configured state.
+    pub state: Option<State>,
+    /// Maximum absolute output current in A.
+    pub current_limit: f32,
+}
+
+impl Default for Constraint {
+    fn default() -> Self {
+        Self {
+            state: None,
+            current_limit: f32::INFINITY,
+        }
+    }
+}
+
+impl Constraint {
+    /// Combine with another constraint. The more restrictive state and limit are kept.
+    pub fn restrict(self, other: Self) -> Self {
+        Self {
+            // `Off` > `Hold` > `On`
+            state: self.state.max(other.state),
+            current_limit: self.current_limit.min(other.current_limit),
+        }
+    }
+
+    /// The effective state given the configured state.
+    pub fn state(&self, state: State) -> State {
+        self.state.map_or(state, |s| s.max(state))
+    }
+
+    /// Clamp an output current to the limit.
+    pub fn clamp(&self, current: f64) -> f64 {
+        let limit = self.current_limit as f64;
+        current.max(-limit).min(limit)
+    }
+}
+
+/// Runtime constraints of all sources for all output channels.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Interlock {
+    constraints: [[Constraint; SOURCES]; 4],
+    effective: [Constraint; 4],
+}
+
+impl Interlock {
+    /// Set the constraint of a source on an output channel.
+    ///
+    /// # Returns
+    /// True if the effective state of the channel changed.
+    pub fn set(&mut self, source: Source, ch: OutputChannelIdx, constraint: Constraint) -> bool {
+        self.constraints[ch as usize][source as usize] = constraint;
+        let effective = self.constraints[ch as usize]
+            .iter()
+            .fold(Constraint::default(), |e, c| e.restrict(*c));
+        let changed = effective.state != self.effective[ch as usize].state;
+        self.effective[ch as usize] = effective;
+        changed
+    }
+
+    /// The constraint of a source on an output channel.
+    pub fn source(&self, source: Source, ch: OutputChannelIdx) -> Constraint {
+        self.constraints[ch as usize][source as usize]
+    }
+
+    /// The effective constraint of an output channel.
+    pub fn get(&self, ch: OutputChannelIdx) -> Constraint {
+        self.effective[ch as usize]
+    }
+}
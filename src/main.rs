@@ -5,11 +5,28 @@
 #![no_std]
 #![no_main]
 
+pub mod alarm;
+pub mod command;
+pub mod config;
+pub mod connection;
+pub mod decoupling;
+pub mod electrical;
+pub mod event;
+pub mod fault;
 pub mod hardware;
+pub mod health;
+pub mod identification;
+pub mod interlock;
+pub mod migration;
 pub mod net;
 pub mod output_channel;
+pub mod profile;
 pub mod settings;
 pub mod statistics;
+pub mod supply;
+pub mod thermal;
+pub mod transaction;
+pub mod validate;
 
 use panic_probe as _; // global panic handler
 use strum::IntoEnumIterator;
@@ -19,25 +36,42 @@ use hardware::{
     adc::{sm::StateMachine, Adc, AdcCode, Ntc, Sensor},
     adc_internal::AdcInternal,
     dac::{Dac, DacCode},
-    gpio::{Gpio, PoePower},
-    hal,
+    fan::Fan,
+    gpio::{Gpio, Led, PoePower},
+    hal::{self, independent_watchdog::IndependentWatchdog},
+    platform::ResetCause,
     pwm::{Limit, Pwm},
-    OutputChannelIdx, SerialTerminal, SystemTimer, Systick, UsbDevice,
+    Lm75, OutputChannelIdx, SerialTerminal, SystemTimer, Systick, UsbDevice,
 };
 
 use rtic_monotonics::Monotonic;
 use rtic_sync::{channel::*, make_channel};
 
+use alarm::{Alarm, AlarmState, AlarmStatus, Measurement};
+use command::{Command, Request, Response};
+use connection::{Connection, ConnectionMonitor, Policy};
+use core::fmt::Write;
+use decoupling::Decoupling;
+use electrical::OuterLoop;
+use event::{Event, EventLog};
+use fault::{Code, FaultState, Faults};
 use fugit::ExtU32;
+use health::{Estimator, TecHealth};
+use identification::{Excitation, Fopdt, Identifier, Trigger};
+use interlock::{Constraint, Interlock, Source};
 use miniconf::{Leaf, StrLeaf, TreeDeserialize, TreeKey, TreeSerialize};
 use net::{
     data_stream::{FrameGenerator, StreamFormat, StreamTarget},
-    Alarm, NetworkState, NetworkUsers,
+    NetworkState, NetworkUsers,
 };
-use output_channel::{OutputChannel, State};
+use num_traits::Float;
+use output_channel::{OutputChannel, Override, State};
 use serde::Serialize;
 use settings::NetSettings;
 use statistics::{Buffer, Statistics};
+use supply::{Rails, Supply, SupplyStatus};
+use thermal::{FanController, FanStatus, Thermal};
+use transaction::Transaction;
 
 #[derive(Clone, Debug, TreeSerialize, TreeDeserialize, TreeKey, Default)]
 pub struct InputChannel {
@@ -56,9 +90,11 @@ pub struct ThermostatEem {
     ///
     /// # Value
     /// Any positive non-zero value. Will be rounded to milliseconds.
+    #[tree(validate=Self::validate)]
     telemetry_period: Leaf<f32>,
 
     /// Input sensor configuration
+    #[tree(validate=Self::validate)]
     input: [[Option<InputChannel>; 4]; 4],
 
     /// Array of settings for the Thermostat output channels.
@@ -69,8 +105,19 @@ pub struct ThermostatEem {
     ///
     /// # Value
     /// See [OutputChannel]
+    #[tree(validate=Self::validate)]
     output: [OutputChannel; 4],
 
+    /// Output decoupling matrix.
+    ///
+    /// # Path
+    /// `decoupling`
+    ///
+    /// # Value
+    /// See [Decoupling]
+    #[tree(validate=Self::validate)]
+    decoupling: Decoupling,
+
     /// Alarm settings.
     ///
     /// # Path
@@ -78,9 +125,70 @@ pub struct ThermostatEem {
     ///
     /// # Value
     /// See [Alarm]
+    #[tree(validate=Self::validate)]
     alarm: Alarm,
 
+    #[tree(validate=Self::validate)]
     stream: Leaf<StreamTarget>,
+
+    /// Board thermal protection settings.
+    ///
+    /// # Path
+    /// `thermal`
+    ///
+    /// # Value
+    /// See [Thermal]
+    #[tree(validate=Self::validate)]
+    thermal: Thermal,
+
+    /// Supply supervision settings.
+    ///
+    /// # Path
+    /// `supply`
+    ///
+    /// # Value
+    /// See [Supply]
+    #[tree(validate=Self::validate)]
+    supply: Supply,
+
+    /// Connection-loss policy settings.
+    ///
+    /// # Path
+    /// `connection`
+    ///
+    /// # Value
+    /// See [Connection]
+    #[tree(validate=Self::validate)]
+    connection: Connection,
+
+    /// Output channel state after a watchdog reset.
+    ///
+    /// All output channels are forced into this state until the `acknowledge` command is received.
+    ///
+    /// # Path
+    /// `safe_state`
+    ///
+    /// # Value
+    /// `"Off"` or `"Hold"`
+    #[tree(validate=Self::validate)]
+    safe_state: Leaf<State>,
+
+    /// Automatic saving of the settings to flash.
+    ///
+    /// The settings that differ from their persisted values are saved once there were no
+    /// further changes for this many seconds. The delay limits flash wear.
+    ///
+    /// # Path
+    /// `autosave`
+    ///
+    /// # Value
+    /// Positive delay in seconds or `None` to disable. See also the `save` command.
+    #[tree(validate=Self::validate)]
+    autosave: Leaf<Option<f32>>,
+
+    /// A change was rejected and is to be reverted (see [Settings::accept]).
+    #[tree(skip)]
+    rejected: bool,
 }
 
 impl Default for ThermostatEem {
@@ -89,9 +197,100 @@ impl Default for ThermostatEem {
             telemetry_period: 1.0.into(),
             input: Default::default(),
             output: Default::default(),
+            decoupling: Default::default(),
             alarm: Default::default(),
             stream: Default::default(),
+            thermal: Default::default(),
+            supply: Default::default(),
+            connection: Default::default(),
+            safe_state: State::Off.into(),
+            autosave: None.into(),
+            rejected: false,
+        }
+    }
+}
+
+impl ThermostatEem {
+    /// The enabled input channels.
+    fn enabled(&self) -> [[bool; 4]; 4] {
+        core::array::from_fn(|adc| core::array::from_fn(|ch| self.input[adc][ch].is_some()))
+    }
+
+    /// Check the consistency of the settings.
+    fn check(&self) -> validate::Result {
+        validate::positive(*self.telemetry_period, "telemetry_period: not positive")?;
+        let enabled = self.enabled();
+        self.output
+            .iter()
+            .try_for_each(|output| output.validate(&enabled))?;
+        self.alarm.validate()?;
+        self.stream.validate()?;
+        self.thermal.validate()?;
+        self.supply.validate()?;
+        self.connection.validate()?;
+        if *self.safe_state == State::On {
+            return Err("safe_state: on");
         }
+        self.autosave.map_or(Ok(()), |delay| {
+            validate::positive(delay, "autosave: not positive")
+        })
+    }
+
+    /// Compare the settings with their persisted values and optionally save the differing values
+    /// to flash.
+    ///
+    /// The input channel configuration is derived from the hardware at boot and not persisted.
+    /// This accesses the flash and is called on a copy of the settings outside of the settings
+    /// lock.
+    ///
+    /// # Args
+    /// * `platform` - The settings platform owning the flash.
+    /// * `save` - Save the differing values.
+    /// * `differs` - Called with the path of each differing value.
+    ///
+    /// # Returns
+    /// The number of differing values.
+    fn persist(
+        &self,
+        platform: &mut settings::SerialSettingsPlatform<Settings, 8>,
+        save: bool,
+        differs: impl FnMut(&str),
+    ) -> Result<usize, command::Error> {
+        let default = Self {
+            input: self.input.clone(),
+            ..Default::default()
+        };
+        platform
+            .persist::<_, 8>("/thermostat_eem", self, &default, save, differs)
+            .map_err(|e| {
+                log::error!("Failed to save settings: {e:?}");
+                command::Error::Storage
+            })
+    }
+
+    /// Load a profile into the settings.
+    ///
+    /// The profile is validated as a whole and rejected if invalid. This accesses the flash and
+    /// is called on a copy of the settings outside of the settings lock. The copy is discarded on
+    /// error.
+    fn load_profile(
+        &mut self,
+        platform: &mut settings::SerialSettingsPlatform<Settings, 8>,
+        name: &str,
+    ) -> Result<(), command::Error> {
+        // Intermediate states while loading may be rejected. Only the result counts.
+        profile::load::<_, _, 8>(platform, name, self)?;
+        self.check().map_err(command::Error::Invalid)
+    }
+
+    /// Validate a settings change.
+    ///
+    /// Called after a setting was deserialized. An invalid change is rejected with an error
+    /// response and reverted (see [Settings::accept]).
+    fn validate(&mut self, depth: usize) -> Result<usize, &'static str> {
+        let result = self.check().map(|()| depth);
+        self.rejected |= result.is_err();
+        result
     }
 }
 
@@ -100,6 +299,145 @@ pub struct Settings {
     pub thermostat_eem: ThermostatEem,
 
     pub net: NetSettings,
+
+    /// The last applied `thermostat_eem` settings.
+    #[tree(skip)]
+    applied: ThermostatEem,
+
+    /// Staged `thermostat_eem` settings of a transaction.
+    #[tree(skip)]
+    transaction: Transaction<ThermostatEem>,
+
+    /// Time of the last unsaved change in milliseconds since boot.
+    #[tree(skip)]
+    modified: Option<u32>,
+
+    /// Runtime overrides of the output channel settings.
+    #[tree(skip)]
+    overrides: [Override; 4],
+}
+
+impl Settings {
+    /// The target of settings writes: the staged settings during a transaction, the active
+    /// settings otherwise.
+    fn target(&mut self) -> &mut ThermostatEem {
+        self.transaction
+            .staged_mut()
+            .unwrap_or(&mut self.thermostat_eem)
+    }
+
+    /// Access the settings with the staged settings in place of the active settings during a
+    /// transaction.
+    fn with_target<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        if let Some(staged) = self.transaction.staged_mut() {
+            core::mem::swap(&mut self.thermostat_eem, staged);
+        }
+        let result = f(self);
+        if let Some(staged) = self.transaction.staged_mut() {
+            core::mem::swap(&mut self.thermostat_eem, staged);
+        }
+        result
+    }
+
+    /// Handle a settings change or a rejected settings change.
+    ///
+    /// Invalid active settings are reverted to the last applied settings. Invalid staged settings
+    /// are kept and refused at commit.
+    ///
+    /// # Returns
+    /// True if the change is to be applied.
+    fn accept(&mut self) -> bool {
+        if let Some(staged) = self.transaction.staged_mut() {
+            staged.rejected = false;
+            return false;
+        }
+        let result = self.thermostat_eem.check();
+        if let Err(reason) = result {
+            log::warn!("Settings change rejected: {reason}");
+            self.thermostat_eem = self.applied.clone();
+        }
+        self.thermostat_eem.rejected = false;
+        result.is_ok()
+    }
+
+    /// Record a settings change for autosave.
+    fn modify(&mut self, time: u32) {
+        self.modified = Some(time);
+    }
+
+    /// Whether the settings are due to be saved automatically.
+    ///
+    /// # Args
+    /// * `time` - Milliseconds since boot.
+    ///
+    /// # Returns
+    /// A copy of the settings to be saved outside of the settings lock. Changes made meanwhile
+    /// are recorded again.
+    fn autosave(&mut self, time: u32) -> Option<ThermostatEem> {
+        let (Some(modified), Some(delay)) = (self.modified, *self.thermostat_eem.autosave) else {
+            return None;
+        };
+        if time.wrapping_sub(modified) < (delay * 1e3) as u32 {
+            return None;
+        }
+        self.modified = None;
+        Some(self.thermostat_eem.clone())
+    }
+
+    /// A copy of the settings to import a configuration document into (see [Settings::import]).
+    ///
+    /// Imports are refused during a transaction as the network settings can not be staged.
+    fn for_import(&self) -> Result<Self, command::Error> {
+        if self.transaction.staged().is_some() {
+            return Err(command::Error::Transaction);
+        }
+        let mut imported = <Self as settings::AppSettings>::new(self.net.clone());
+        imported.thermostat_eem = self.thermostat_eem.clone();
+        Ok(imported)
+    }
+
+    /// Import a configuration document.
+    ///
+    /// The document is validated as a whole and rejected if invalid. The imported network
+    /// settings are saved to flash. This accesses the flash and is called on a copy of the
+    /// settings outside of the settings lock.
+    ///
+    /// # Returns
+    /// The number of imported values.
+    fn import(
+        &mut self,
+        platform: &mut settings::SerialSettingsPlatform<Self, 8>,
+        document: &[u8],
+    ) -> Result<usize, command::Error> {
+        let count = config::import::<_, 8>(self, document)?;
+        self.thermostat_eem
+            .check()
+            .map_err(command::Error::Invalid)?;
+        platform
+            .persist::<_, 1>(
+                "/net",
+                &self.net,
+                &NetSettings::new(self.net.mac),
+                true,
+                |_| {},
+            )
+            .map_err(|e| {
+                log::error!("Failed to save network settings: {e:?}");
+                command::Error::Storage
+            })?;
+        Ok(count)
+    }
+
+    /// Validate the staged settings and make them active.
+    fn commit(&mut self) -> Result<(), command::Error> {
+        let staged = self
+            .transaction
+            .staged()
+            .ok_or(command::Error::Transaction)?;
+        staged.check().map_err(command::Error::Invalid)?;
+        self.thermostat_eem = self.transaction.take()?;
+        Ok(())
+    }
 }
 
 impl settings::AppSettings for Settings {
@@ -107,6 +445,10 @@ impl settings::AppSettings for Settings {
         Self {
             net,
             thermostat_eem: ThermostatEem::default(),
+            applied: ThermostatEem::default(),
+            transaction: Default::default(),
+            modified: None,
+            overrides: Default::default(),
         }
     }
 
@@ -117,10 +459,9 @@ impl settings::AppSettings for Settings {
 
 impl serial_settings::Settings for Settings {
     fn reset(&mut self) {
-        *self = Self {
-            thermostat_eem: ThermostatEem::default(),
-            net: NetSettings::new(self.net.mac),
-        }
+        // The applied and staged settings are runtime state and retained.
+        self.thermostat_eem = ThermostatEem::default();
+        self.net = NetSettings::new(self.net.mac);
     }
 }
 
@@ -141,6 +482,8 @@ pub struct Monitor {
     poe: PoePower,
     /// Overtemperature status.
     overtemp: bool,
+    /// Board temperature (LM75) in °C. `NaN` if unavailable.
+    board_temperature: f32,
 }
 
 /// Thermostat-EEM Telemetry.
@@ -152,8 +495,25 @@ pub struct Telemetry {
     statistics: [[Option<Statistics>; 4]; 4],
     /// Alarm status for each enabled input channel. `None` for disabled channels.
     alarm: [[Option<bool>; 4]; 4],
+    /// See [AlarmStatus]
+    alarm_status: AlarmStatus,
     /// Output current in Amperes for each Thermostat output channel.
     output_current: [f32; 4],
+    /// FOPDT model from the last identification step response for each output channel.
+    identification: [Option<Fopdt>; 4],
+    /// TEC health for each output channel. `None` for disabled channels.
+    tec: [Option<TecHealth>; 4],
+    /// Effective runtime constraint for each output channel. See [Constraint].
+    interlock: [Constraint; 4],
+    /// Overtemperature condition. The outputs are latched off by the `Overtemperature` fault.
+    /// See [Thermal].
+    overtemperature: bool,
+    /// See [FanStatus]
+    fan: FanStatus,
+    /// See [SupplyStatus]
+    supply: SupplyStatus,
+    /// Fault state of each output channel. See [FaultState].
+    fault: [FaultState; 4],
 }
 
 #[repr(C)]
@@ -163,6 +523,24 @@ struct Stream {
     current: [f32; 4],
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct IdentificationStream {
+    temperature: [[f32; 4]; 4],
+    current: [f32; 4],
+    input: [f32; 4],
+    excitation: [f32; 4],
+}
+
+fn add_batch(generator: &mut FrameGenerator, b: &[u8]) {
+    generator.add(|buf| {
+        for (b, s) in buf.iter_mut().zip(b.iter()) {
+            b.write(*s);
+        }
+        b.len()
+    });
+}
+
 #[derive(Clone, Debug)]
 struct Data {
     phy: AdcPhy,
@@ -183,6 +561,12 @@ mod app {
         gpio: Gpio,
         temperature: [[f64; 4]; 4], // input temperature array in °C. Organized as [Adc_idx,  Channel_idx].
         statistics: [[Buffer; 4]; 4], // input statistics buffer for processing telemetry. Organized as [Adc_idx,  Channel_idx].
+        interlock: Interlock,
+        fan: Fan,
+        alarm_state: AlarmState,
+        fault: Faults,
+        events: EventLog,
+        identify: [Trigger; 4],
     }
 
     #[local]
@@ -192,9 +576,19 @@ mod app {
         dac: Dac,
         pwm: Pwm,
         adc_internal: AdcInternal,
+        lm75: Option<Lm75>,
+        fan_controller: FanController,
+        supply: supply::Supervisor,
+        connection: ConnectionMonitor,
+        outer: [OuterLoop; 4],
+        estimator: [Estimator; 4],
         iir_state: [[f64; 4]; 4],
+        feedforward_state: [[f64; 4]; 4],
+        identifier: [Identifier; 4],
+        current: [f64; 4],
         generator: FrameGenerator,
         process: Sender<'static, Data, 4>,
+        watchdog: IndependentWatchdog,
     }
 
     #[init]
@@ -223,6 +617,47 @@ mod app {
             }
         }
 
+        // The settings loaded from flash are validated as a whole once the input channel
+        // configuration is known.
+        if let Err(reason) = thermostat.settings.thermostat_eem.check() {
+            log::error!("Invalid settings loaded from flash, using defaults: {reason}");
+            thermostat.settings.thermostat_eem = ThermostatEem {
+                input: thermostat.settings.thermostat_eem.input.clone(),
+                ..Default::default()
+            };
+        }
+
+        // Check that the exported default settings can be imported again.
+        #[cfg(debug_assertions)]
+        {
+            let defaults = ThermostatEem {
+                input: thermostat.settings.thermostat_eem.input.clone(),
+                ..Default::default()
+            };
+            let mut imported = defaults.clone();
+            let document = thermostat.usb_serial.platform_mut().import.as_deref_mut();
+            let result = config::round_trip::<_, 8>(&defaults, &mut imported, document.unwrap())
+                .and_then(|()| imported.check().map_err(command::Error::Invalid));
+            assert!(result.is_ok(), "Default settings round trip: {result:?}");
+        }
+
+        // Load the boot default profile.
+        let platform = thermostat.usb_serial.platform_mut();
+        match profile::default(platform) {
+            Ok(Some(name)) => {
+                let mut loaded = thermostat.settings.thermostat_eem.clone();
+                match loaded.load_profile(platform, &name) {
+                    Ok(()) => {
+                        thermostat.settings.thermostat_eem = loaded;
+                        log::info!("Loaded boot default profile `{name}`");
+                    }
+                    Err(e) => log::warn!("Failed to load boot default profile `{name}`: {e:?}"),
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read boot default profile: {e:?}"),
+        }
+
         let mut network = NetworkUsers::new(
             thermostat.net.stack,
             thermostat.net.phy,
@@ -236,15 +671,48 @@ mod app {
 
         let (process, r) = make_channel!(Data, 4);
 
+        let mut events = EventLog::default();
+        events.push(0, Event::Boot(thermostat.reset_cause));
+
+        let mut interlock = Interlock::default();
+        if thermostat.reset_cause == ResetCause::Watchdog {
+            let state = *thermostat.settings.thermostat_eem.safe_state;
+            log::warn!("Watchdog reset: outputs forced {state:?} until acknowledged");
+            for ch in OutputChannelIdx::iter() {
+                interlock.set(
+                    Source::Watchdog,
+                    ch,
+                    Constraint {
+                        state: Some(state),
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+
+        // The watchdog is fed by `process`. The timeout needs to cover flash sector erases in
+        // lower priority tasks which hold the settings lock and thereby stall `process`.
+        thermostat.watchdog.start(4.secs());
+
         let local = Local {
             usb_terminal: thermostat.usb_serial,
             adc_sm: thermostat.adc_sm,
             pwm: thermostat.pwm,
             adc_internal: thermostat.adc_internal,
+            lm75: thermostat.lm75,
+            fan_controller: Default::default(),
+            supply: Default::default(),
+            connection: Default::default(),
+            outer: Default::default(),
+            estimator: Default::default(),
             iir_state: Default::default(),
+            feedforward_state: Default::default(),
+            identifier: Default::default(),
+            current: Default::default(),
             dac: thermostat.dac,
             generator,
             process,
+            watchdog: thermostat.watchdog,
         };
 
         let shared = Shared {
@@ -255,6 +723,12 @@ mod app {
             gpio: thermostat.gpio,
             temperature: Default::default(),
             statistics: Default::default(),
+            interlock,
+            fan: thermostat.fan,
+            alarm_state: Default::default(),
+            fault: Default::default(),
+            events,
+            identify: Default::default(),
         };
 
         process::spawn(r).unwrap();
@@ -263,63 +737,309 @@ mod app {
         settings::spawn().unwrap();
         ethernet_link::spawn().unwrap();
         telemetry::spawn().unwrap();
+        supervisor::spawn().unwrap();
         alarm::spawn().unwrap();
         usb::spawn().unwrap();
 
         (shared, local)
     }
 
-    #[idle(shared=[network, settings])]
+    #[idle(shared=[network, settings, events])]
     fn idle(mut c: idle::Context) -> ! {
         loop {
-            (&mut c.shared.network, &mut c.shared.settings).lock(|net, settings| {
-                match net.update(&mut settings.thermostat_eem) {
-                    NetworkState::SettingsChanged => settings::spawn().unwrap(),
-                    NetworkState::Updated => {}
-                    NetworkState::NoChange => {}
-                }
-            })
+            (
+                &mut c.shared.network,
+                &mut c.shared.settings,
+                &mut c.shared.events,
+            )
+                .lock(|net, settings, events| {
+                    match net.update(settings.target()) {
+                        NetworkState::SettingsChanged => {
+                            if settings.accept() {
+                                // The settings task may already be pending, which applies the
+                                // change as well.
+                                settings::spawn().ok();
+                                let time = Systick::now().ticks();
+                                settings.modify(time);
+                                events.push(time, Event::Settings);
+                            }
+                        }
+                        // Rejected changes are kept by the settings tree and reverted here.
+                        NetworkState::Updated | NetworkState::NoChange => {
+                            if settings.target().rejected {
+                                settings.accept();
+                            }
+                        }
+                    }
+                })
         }
     }
 
-    #[task(priority = 1, local=[pwm], shared=[network, settings, gpio])]
+    #[task(priority = 1, local=[pwm], shared=[network, settings, gpio, interlock, fault])]
     async fn settings(c: settings::Context) {
         let pwm = c.local.pwm;
-        (c.shared.network, c.shared.gpio, c.shared.settings).lock(|network, gpio, settings| {
-            for (ch, s) in OutputChannelIdx::iter().zip(settings.thermostat_eem.output.iter_mut()) {
-                s.finalize_settings(); // clamp limits and normalize weights
-                pwm.set_limit(Limit::Voltage(ch), *s.voltage_limit).unwrap();
-                let [pos, neg] = s.current_limits();
-                pwm.set_limit(Limit::PositiveCurrent(ch), pos).unwrap();
-                pwm.set_limit(Limit::NegativeCurrent(ch), neg).unwrap();
-                gpio.set_shutdown(ch, (*s.state == State::Off).into());
-                gpio.set_led(ch.into(), (*s.state != State::Off).into()); // fix leds to channel state
-            }
+        (
+            c.shared.network,
+            c.shared.gpio,
+            c.shared.settings,
+            c.shared.interlock,
+            c.shared.fault,
+        )
+            .lock(|network, gpio, settings, interlock, fault| {
+                for (ch, s) in
+                    OutputChannelIdx::iter().zip(settings.thermostat_eem.output.iter_mut())
+                {
+                    // clamp limits and normalize weights
+                    if let Err(code) = s.finalize_settings() {
+                        fault.raise(interlock, ch, code);
+                    }
+                    // The runtime current limit (e.g. the power limit) also constrains the PWM
+                    // limits with the same headroom.
+                    let limit = interlock.get(ch).current_limit + 0.05 * Pwm::MAX_CURRENT_LIMIT;
+                    let [pos, neg] = s.current_limits();
+                    let (pos, neg) = (pos.min(limit), neg.max(-limit));
+                    if pwm
+                        .set_limit(Limit::Voltage(ch), *s.voltage_limit)
+                        .and_then(|_| pwm.set_limit(Limit::PositiveCurrent(ch), pos))
+                        .and_then(|_| pwm.set_limit(Limit::NegativeCurrent(ch), neg))
+                        .is_err()
+                    {
+                        fault.raise(interlock, ch, Code::Limit);
+                    }
+                    let state = interlock.get(ch).state(*s.state);
+                    gpio.set_shutdown(ch, (state == State::Off).into());
+                    gpio.set_led(ch.into(), (state != State::Off).into()); // fix leds to channel state
+                }
+
+                network.direct_stream(*settings.thermostat_eem.stream);
 
-            network.direct_stream(*settings.thermostat_eem.stream);
-        });
+                // Report the effective values and keep them to revert rejected changes.
+                let output = &settings.thermostat_eem.output;
+                network
+                    .telemetry
+                    .publish_applied(core::array::from_fn(|idx| output[idx].applied()));
+                settings.applied = settings.thermostat_eem.clone();
+            });
     }
 
-    #[task(priority = 1, local=[adc_internal], shared=[network, settings, telemetry, gpio, statistics])]
-    async fn telemetry(mut c: telemetry::Context) {
+    /// Slow supervisory loop.
+    ///
+    /// Reads the internal ADC monitor measurements and the board temperature, runs the electrical
+    /// outer loops, monitors the TEC health, applies the board thermal protection and controls
+    /// the fan.
+    #[task(priority = 1, local=[adc_internal, lm75, fan_controller, supply, outer, estimator], shared=[settings, telemetry, gpio, interlock, temperature, fan, fault, events])]
+    async fn supervisor(mut c: supervisor::Context) {
+        // Supervisor period in seconds
+        const PERIOD: f32 = 0.1;
+        let mut blink = false;
+        let mut last = Telemetry::default();
+        // Current limits last applied to the PWM.
+        let mut pwm_limit = [f32::INFINITY; 4];
         loop {
-            let mut telemetry: Telemetry = c.shared.telemetry.lock(|telemetry| *telemetry);
             let adc_int = &mut c.local.adc_internal;
-            telemetry.monitor.p3v3_voltage = adc_int.read_p3v3_voltage();
-            telemetry.monitor.p5v_voltage = adc_int.read_p5v_voltage();
-            telemetry.monitor.p12v_voltage = adc_int.read_p12v_voltage();
-            telemetry.monitor.p12v_current = adc_int.read_p12v_current();
+            let mut monitor = Monitor {
+                p3v3_voltage: adc_int.read_p3v3_voltage(),
+                p5v_voltage: adc_int.read_p5v_voltage(),
+                p12v_voltage: adc_int.read_p12v_voltage(),
+                p12v_current: adc_int.read_p12v_current(),
+                ..Default::default()
+            };
             for ch in OutputChannelIdx::iter() {
                 let idx = ch as usize;
-                telemetry.monitor.output_vref[idx] = adc_int.read_output_vref(ch);
-                telemetry.monitor.output_voltage[idx] = adc_int.read_output_voltage(ch);
-                telemetry.monitor.output_current[idx] = adc_int.read_output_current(ch);
+                monitor.output_vref[idx] = adc_int.read_output_vref(ch);
+                monitor.output_voltage[idx] = adc_int.read_output_voltage(ch);
+                monitor.output_current[idx] = adc_int.read_output_current(ch);
+            }
+            c.shared.gpio.lock(|gpio| {
+                monitor.overtemp = gpio.overtemp();
+                monitor.poe = gpio.poe();
+            });
+            monitor.board_temperature = c
+                .local
+                .lm75
+                .as_mut()
+                .map_or(f32::NAN, |lm75| lm75.read_temperature().unwrap_or(f32::NAN));
+
+            let outer = &mut c.local.outer;
+            let estimator = &mut c.local.estimator;
+            let supervisor = &mut c.local.supply;
+            let mut tec = [None; 4];
+            let mut constraints = [Constraint::default(); 4];
+            let mut overtemperature = false;
+            let mut supply = SupplyStatus::default();
+            let mut faults = [FaultState::default(); 4];
+            let changed = (
+                &mut c.shared.settings,
+                &mut c.shared.interlock,
+                &mut c.shared.fault,
+            )
+                .lock(|settings, interlock, fault| {
+                    let mut changed = false;
+                    let rails = Rails {
+                        p3v3: monitor.p3v3_voltage,
+                        p5v: monitor.p5v_voltage,
+                        p12v: monitor.p12v_voltage,
+                        p12v_current: monitor.p12v_current,
+                        poe: monitor.poe,
+                        output_current: monitor.output_current,
+                    };
+                    let enabled = settings
+                        .thermostat_eem
+                        .output
+                        .each_ref()
+                        .map(|channel| *channel.state != State::Off);
+                    let (supply_constraints, status) =
+                        supervisor.update(&settings.thermostat_eem.supply, &rails, enabled, PERIOD);
+                    supply = status;
+                    let thermal = settings.thermostat_eem.thermal;
+                    let derating = thermal.derating(monitor.board_temperature);
+                    overtemperature =
+                        thermal.overtemperature(monitor.board_temperature, monitor.overtemp);
+                    if overtemperature && !last.overtemperature {
+                        log::error!(
+                            "Overtemperature ({} °C): outputs shut down",
+                            monitor.board_temperature
+                        );
+                    }
+                    for ch in OutputChannelIdx::iter() {
+                        let idx = ch as usize;
+                        let channel = &mut settings.thermostat_eem.output[idx];
+                        let state = interlock.get(ch).state(*channel.state);
+                        let (command, current_limit) = outer[idx].update(
+                            &channel.electrical,
+                            monitor.output_voltage[idx],
+                            monitor.output_current[idx],
+                            channel.output_limits().map(|l| l as _),
+                            state == State::On,
+                            PERIOD,
+                        );
+                        settings.overrides[idx].command = channel.electrical.command(command);
+                        tec[idx] = (state != State::Off).then(|| {
+                            estimator[idx].update(
+                                &channel.health,
+                                monitor.output_voltage[idx],
+                                monitor.output_current[idx],
+                                PERIOD,
+                            )
+                        });
+                        changed |= interlock.set(
+                            Source::Power,
+                            ch,
+                            Constraint {
+                                current_limit,
+                                ..Default::default()
+                            },
+                        );
+                        let [min, max] = channel.output_limits();
+                        changed |= interlock.set(
+                            Source::Overtemperature,
+                            ch,
+                            Constraint {
+                                current_limit: derating * min.abs().max(max.abs()) as f32,
+                                ..Default::default()
+                            },
+                        );
+                        changed |= interlock.set(Source::Supply, ch, supply_constraints[idx]);
+                        // Update the PWM limits on significant changes of the current limit.
+                        let limit = interlock.get(ch).current_limit;
+                        if limit != pwm_limit[idx] && !((limit - pwm_limit[idx]).abs() < 0.01) {
+                            pwm_limit[idx] = limit;
+                            changed = true;
+                        }
+                        // The fault latches the shutdown until it is reset.
+                        if overtemperature {
+                            changed |= fault.raise(interlock, ch, Code::Overtemperature);
+                        }
+                        if tec[idx].is_some_and(|tec| tec.fault()) {
+                            changed |= fault.raise(interlock, ch, Code::Tec);
+                        }
+                        constraints[idx] = interlock.get(ch);
+                    }
+                    faults = fault.all();
+                    changed
+                });
+            if changed {
+                // The settings task may already be pending, which applies the change as well.
+                settings::spawn().ok();
             }
+
+            let fan_settings = c
+                .shared
+                .settings
+                .lock(|settings| settings.thermostat_eem.thermal.fan);
+            let temperature = match *fan_settings.input {
+                None => monitor.board_temperature,
+                Some([adc, ch]) => c.shared.temperature.lock(|temperature| {
+                    temperature
+                        .get(adc as usize)
+                        .and_then(|t| t.get(ch as usize))
+                        .map_or(f32::NAN, |t| *t as _)
+                }),
+            };
+            let fan = c.shared.fan.lock(|fan| {
+                let status =
+                    c.local
+                        .fan_controller
+                        .update(&fan_settings, temperature, fan.rpm(), PERIOD);
+                fan.set_duty(status.duty).ok();
+                status
+            });
+
+            c.shared.telemetry.lock(|telemetry| {
+                telemetry.monitor = monitor;
+                telemetry.fan = fan;
+                telemetry.tec = tec;
+                telemetry.interlock = constraints;
+                telemetry.overtemperature = overtemperature;
+                telemetry.supply = supply;
+                telemetry.fault = faults;
+            });
+
+            c.shared.events.lock(|events| {
+                let time = Systick::now().ticks();
+                if overtemperature && !last.overtemperature {
+                    events.push(time, Event::Overtemperature);
+                }
+                if supply.undervoltage && !last.supply.undervoltage {
+                    events.push(time, Event::Undervoltage);
+                }
+                for (channel, (fault, last)) in faults.iter().zip(last.fault.iter()).enumerate() {
+                    for code in Code::iter()
+                        .filter(|code| fault.codes.contains(*code) && !last.codes.contains(*code))
+                    {
+                        events.push(
+                            time,
+                            Event::Fault {
+                                channel: channel as _,
+                                code,
+                            },
+                        );
+                    }
+                }
+            });
+            last.overtemperature = overtemperature;
+            last.supply = supply;
+            last.fault = faults;
+
+            // Blink the LEDs of faulted output channels.
+            blink = !blink;
             c.shared.gpio.lock(|gpio| {
-                telemetry.monitor.overtemp = gpio.overtemp();
-                telemetry.monitor.poe = gpio.poe();
+                for ch in OutputChannelIdx::iter() {
+                    if faults[ch as usize].faulted() {
+                        gpio.set_led(ch.into(), blink.into());
+                    }
+                }
             });
 
+            Systick::delay(((PERIOD * 1000.0) as u32).millis()).await;
+        }
+    }
+
+    #[task(priority = 1, shared=[network, settings, telemetry, statistics])]
+    async fn telemetry(mut c: telemetry::Context) {
+        loop {
+            let mut telemetry: Telemetry = c.shared.telemetry.lock(|telemetry| *telemetry);
+
             // Finalize temperature telemetry and reset buffer
             for phy_i in 0..4 {
                 for cfg_i in 0..4 {
@@ -334,140 +1054,729 @@ mod app {
                 .network
                 .lock(|network| network.telemetry.publish(&telemetry));
 
-            // TODO: validate telemetry period.
             let telemetry_period = c
                 .shared
                 .settings
                 .lock(|settings| settings.thermostat_eem.telemetry_period);
-            Systick::delay((*telemetry_period as u32).secs()).await;
+            Systick::delay(((*telemetry_period * 1e3).round() as u32).millis()).await;
         }
     }
 
-    #[task(priority = 1, shared=[network, settings, temperature, telemetry])]
+    #[task(priority = 1, shared=[network, settings, temperature, telemetry, alarm_state, gpio, interlock, events])]
     async fn alarm(mut c: alarm::Context) {
+        let mut asserted = false;
         loop {
             let alarm = c
                 .shared
                 .settings
                 .lock(|settings| settings.thermostat_eem.alarm.clone());
-            if *alarm.armed {
-                let temperatures = c.shared.temperature.lock(|temp| *temp);
-                let mut alarms = [[None; 4]; 4];
-                let alarm_state = alarm
-                    .temperature_limits
-                    .as_flattened()
-                    .iter()
-                    .zip(temperatures.as_flattened().iter())
-                    .zip(alarms.as_flattened_mut().iter_mut())
-                    .any(|((l, t), a)| {
-                        *a = l.map(|l| !(*l[0]..*l[1]).contains(&(*t as _)));
-                        a.unwrap_or_default()
+            let (constraints, leds) = if *alarm.armed {
+                let measurement = (
+                    &mut c.shared.settings,
+                    &mut c.shared.interlock,
+                    &mut c.shared.telemetry,
+                    &mut c.shared.temperature,
+                )
+                    .lock(|settings, interlock, telemetry, temperature| {
+                        let overrides = &settings.overrides;
+                        let settings = &settings.thermostat_eem;
+                        let mut measurement = Measurement {
+                            fan_stall: telemetry.fan.stall,
+                            fan_rpm: telemetry.fan.rpm,
+                            supply: [
+                                telemetry.monitor.p3v3_voltage,
+                                telemetry.monitor.p5v_voltage,
+                                telemetry.monitor.p12v_voltage,
+                            ],
+                            ..Default::default()
+                        };
+                        for ((t, input), temperature) in measurement
+                            .temperature
+                            .as_flattened_mut()
+                            .iter_mut()
+                            .zip(settings.input.as_flattened())
+                            .zip(temperature.as_flattened())
+                        {
+                            *t = input.as_ref().map(|_| *temperature as f32);
+                        }
+                        for ch in OutputChannelIdx::iter() {
+                            let idx = ch as usize;
+                            let channel = &settings.output[idx];
+                            let constraint = interlock.get(ch);
+                            measurement.drift[idx] = telemetry.tec[idx]
+                                .map(|tec| tec.drift())
+                                .filter(|drift| drift.is_finite());
+                            if constraint.state(*channel.state) != State::On {
+                                continue;
+                            }
+                            if overrides[idx].command.is_none() {
+                                measurement.deviation[idx] = Some(
+                                    (channel.input(temperature)
+                                        - channel.setpoint(&overrides[idx]) as f64)
+                                        as f32,
+                                );
+                            }
+                            let [min, max] = channel.output_limits();
+                            let current = telemetry.output_current[idx];
+                            measurement.headroom[idx] = Some(
+                                (constraint.clamp(max) as f32 - current)
+                                    .min(current - constraint.clamp(min) as f32),
+                            );
+                        }
+                        measurement
                     });
-                c.shared
-                    .telemetry
-                    .lock(|telemetry| telemetry.alarm = alarms);
-                c.shared
-                    .network
-                    .lock(|net| net.telemetry.publish_alarm(&alarm.target, &alarm_state));
+                let (alarms, status) = c
+                    .shared
+                    .alarm_state
+                    .lock(|state| state.update(&alarm, &measurement, Systick::now().ticks()));
+                c.shared.telemetry.lock(|telemetry| {
+                    telemetry.alarm = alarms;
+                    telemetry.alarm_status = status;
+                });
+                if status.asserted != asserted {
+                    asserted = status.asserted;
+                    c.shared
+                        .events
+                        .lock(|events| events.push(Systick::now().ticks(), Event::Alarm(asserted)));
+                }
+                (&mut c.shared.network, &mut c.shared.alarm_state).lock(|net, state| {
+                    if (state.pending() || !*alarm.on_change)
+                        && net.telemetry.publish_alarm(&alarm.target, &state.message())
+                    {
+                        state.published();
+                    }
+                });
+                c.shared.alarm_state.lock(|state| state.actions(&alarm))
+            } else {
+                c.shared.alarm_state.lock(|state| state.disarm());
+                asserted = false;
+                Default::default()
+            };
+
+            // Apply the alarm actions.
+            let changed = c.shared.interlock.lock(|interlock| {
+                OutputChannelIdx::iter().fold(false, |changed, ch| {
+                    interlock.set(Source::Alarm, ch, constraints[ch as usize]) | changed
+                })
+            });
+            if changed {
+                settings::spawn().ok();
             }
+            c.shared.gpio.lock(|gpio| {
+                for (led, on) in [Led::Led4, Led::Led5, Led::Led6, Led::Led7]
+                    .into_iter()
+                    .zip(leds)
+                {
+                    gpio.set_led(led, on.into());
+                }
+            });
             // Note that you have to wait for a full period of the previous setting first for a change of period to take affect.
             Systick::delay(((*alarm.period * 1000.0) as u32).millis()).await;
         }
     }
 
     // Higher priority than telemetry but lower than adc data readout.
-    #[task(priority = 2, shared=[temperature, statistics, telemetry, settings], local=[iir_state, generator, dac])]
+    #[task(priority = 2, shared=[temperature, statistics, telemetry, settings, interlock, fault, identify], local=[iir_state, feedforward_state, identifier, current, generator, dac, watchdog])]
     async fn process(mut c: process::Context, mut data: Receiver<'static, Data, 4>) {
         while let Ok(Data { phy, ch, adc_code }) = data.recv().await {
-            let temp = c.shared.settings.lock(|settings| {
-                let input = settings.thermostat_eem.input[phy as usize][ch]
+            let Some(temp) = c.shared.settings.lock(|settings| {
+                settings.thermostat_eem.input[phy as usize][ch]
                     .as_ref()
-                    .unwrap();
-                input.sensor.convert(adc_code)
-            });
+                    .map(|input| input.sensor.convert(adc_code))
+            }) else {
+                log::warn!("Sample from disabled input channel {phy:?} {ch}");
+                continue;
+            };
             (
                 &mut c.shared.temperature,
                 &mut c.shared.statistics,
                 &mut c.shared.telemetry,
                 &mut c.shared.settings,
+                &mut c.shared.interlock,
+                &mut c.shared.fault,
+                &mut c.shared.identify,
             )
-                .lock(|temperature, statistics, telemetry, settings| {
-                    temperature[phy as usize][ch] = temp;
-                    statistics[phy as usize][ch].update(temp as _);
-
-                    // Start processing when the last ADC has been read out.
-                    // This implies a zero-order hold (aka the input sample will not be updated at every signal processing step) if more than one channel is enabled on an ADC.
-                    if phy != AdcPhy::Three {
-                        return;
-                    }
+                .lock(
+                    |temperature, statistics, telemetry, settings, interlock, fault, identify| {
+                        temperature[phy as usize][ch] = temp;
+                        statistics[phy as usize][ch].update(temp as _);
 
-                    for ch in OutputChannelIdx::iter() {
-                        let idx = ch as usize;
-                        let current = settings.thermostat_eem.output[idx]
-                            .update(temperature, &mut c.local.iir_state[idx])
-                            as f32;
-                        telemetry.output_current[idx] = current;
-                        c.local.dac.set(ch, DacCode::try_from(current).unwrap());
-                    }
-                    let mut s = Stream {
-                        temperature: [[0.0; 4]; 4],
-                        current: telemetry.output_current,
-                    };
-                    for (t, u) in s
-                        .temperature
-                        .as_flattened_mut()
-                        .iter_mut()
-                        .zip(temperature.as_flattened().iter())
-                    {
-                        *t = *u as _;
-                    }
-                    let b = bytemuck::bytes_of(&s);
-                    c.local.generator.add(|buf| {
-                        for (b, s) in buf.iter_mut().zip(b.iter()) {
-                            b.write(*s);
+                        // Start processing when the last ADC has been read out.
+                        // This implies a zero-order hold (aka the input sample will not be updated at every signal processing step) if more than one channel is enabled on an ADC.
+                        if phy != AdcPhy::Three {
+                            return;
                         }
-                        b.len()
-                    });
-                });
+
+                        let mut input = [0.0; 4];
+                        let mut excitation = [0.0; 4];
+                        let mut closed_loop = [false; 4];
+                        let mut output = [0.0; 4];
+                        let mut changed = false;
+                        for (ch, y) in OutputChannelIdx::iter().zip(output.iter_mut()) {
+                            let idx = ch as usize;
+                            let channel = &mut settings.thermostat_eem.output[idx];
+                            let identifier = &mut c.local.identifier[idx];
+                            input[idx] = channel.input(temperature);
+                            excitation[idx] = identifier.update(
+                                &channel.identification,
+                                core::mem::take(&mut identify[idx]),
+                                input[idx],
+                            );
+                            let state = interlock.get(ch).state(*channel.state);
+                            let hold = identifier.open_loop() || state != *channel.state;
+                            closed_loop[idx] = state == State::On && !hold;
+                            if closed_loop[idx] && !input[idx].is_finite() {
+                                changed |= fault.raise(interlock, ch, Code::Sensor);
+                            }
+                            *y = channel.update(
+                                temperature,
+                                &mut c.local.iir_state[idx],
+                                &mut c.local.feedforward_state[idx],
+                                hold,
+                                &settings.overrides[idx],
+                            );
+                            telemetry.identification[idx] = identifier.model();
+                        }
+                        let mut limited = output;
+                        let limits = settings
+                            .thermostat_eem
+                            .output
+                            .each_ref()
+                            .map(|o| o.output_limits());
+                        let current = if *settings.thermostat_eem.decoupling.enable {
+                            settings
+                                .thermostat_eem
+                                .decoupling
+                                .update(&mut limited, &limits)
+                        } else {
+                            output
+                        };
+                        let mut constrained = [0.0; 4];
+                        for ch in OutputChannelIdx::iter() {
+                            let idx = ch as usize;
+                            let [min, max] = limits[idx];
+                            let unlimited = (current[idx] + excitation[idx]).max(min).min(max);
+                            let limited = interlock.get(ch).clamp(unlimited);
+                            let slewed = settings.thermostat_eem.output[idx]
+                                .limit_slew(limited, c.local.current[idx]);
+                            constrained[idx] = unlimited - slewed;
+                            c.local.current[idx] = slewed;
+                            let current = slewed as f32;
+                            telemetry.output_current[idx] = current;
+                            if let Err(e) = DacCode::try_from(current)
+                                .and_then(|code| c.local.dac.set(ch, code))
+                            {
+                                log::error!("DAC error on output channel {ch:?}: {e:?}");
+                                changed |= fault.raise(interlock, ch, Code::Dac);
+                            }
+                        }
+                        // Let the loops know about the scaled, constrained and rate limited
+                        // outputs (anti-windup). With decoupling, the output current constraints
+                        // are mapped back to the loop outputs.
+                        let constrained = if *settings.thermostat_eem.decoupling.enable {
+                            settings
+                                .thermostat_eem
+                                .decoupling
+                                .solve(constrained)
+                                .unwrap_or_default()
+                        } else {
+                            constrained
+                        };
+                        for ch in OutputChannelIdx::iter() {
+                            let idx = ch as usize;
+                            // Held and disabled channels keep their state.
+                            if closed_loop[idx] {
+                                output_channel::anti_windup(
+                                    &mut c.local.iir_state[idx],
+                                    output[idx],
+                                    limited[idx] - constrained[idx],
+                                );
+                            }
+                        }
+                        if changed {
+                            settings::spawn().ok();
+                        }
+                        // ADC samples arrive and the outputs are updated.
+                        c.local.watchdog.feed();
+                        let mut temperatures = [[0.0; 4]; 4];
+                        for (t, u) in temperatures
+                            .as_flattened_mut()
+                            .iter_mut()
+                            .zip(temperature.as_flattened().iter())
+                        {
+                            *t = *u as _;
+                        }
+                        if c.local.identifier.iter().any(|i| i.is_active()) {
+                            let s = IdentificationStream {
+                                temperature: temperatures,
+                                current: telemetry.output_current,
+                                input: input.map(|i| i as _),
+                                excitation: excitation.map(|e| e as _),
+                            };
+                            c.local
+                                .generator
+                                .set_format(StreamFormat::ThermostatEemIdentification as u8);
+                            add_batch(c.local.generator, bytemuck::bytes_of(&s));
+                        } else {
+                            let s = Stream {
+                                temperature: temperatures,
+                                current: telemetry.output_current,
+                            };
+                            c.local
+                                .generator
+                                .set_format(StreamFormat::ThermostatEem as u8);
+                            add_batch(c.local.generator, bytemuck::bytes_of(&s));
+                        }
+                    },
+                );
         }
     }
 
     #[task(priority = 3, binds = EXTI15_10, local=[adc_sm, process])]
     fn adc_readout(c: adc_readout::Context) {
-        let (phy, ch, adc_code) = c.local.adc_sm.handle_interrupt();
+        let (phy, ch, adc_code) = match c.local.adc_sm.handle_interrupt() {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("ADC readout error: {e:?}");
+                return;
+            }
+        };
         if let Err(e) = c.local.process.try_send(Data { phy, ch, adc_code }) {
             log::warn!("Processing queue overflow: {e:?}");
         }
     }
 
-    #[task(priority = 1, shared=[usb, settings], local=[usb_terminal])]
+    #[task(priority = 1, shared=[usb, network, settings, interlock, alarm_state, fault, events, identify], local=[usb_terminal])]
     async fn usb(mut c: usb::Context) {
         loop {
+            // Take back the configuration document buffer from the MQTT client. It may hold the
+            // document of a pending MQTT import.
+            if let Some(document) = c.shared.network.lock(|net| net.telemetry.take_document()) {
+                c.local.usb_terminal.platform_mut().import = Some(document);
+            }
+
             // Handle the USB serial terminal.
             c.shared.usb.lock(|usb| {
                 usb.poll(&mut [c.local.usb_terminal.interface_mut().inner_mut()]);
             });
 
-            c.shared.settings.lock(|settings| {
-                if c.local.usb_terminal.poll(settings).unwrap() {
-                    settings::spawn().unwrap()
+            if c.local.usb_terminal.platform_mut().raw {
+                c.local.usb_terminal.platform_mut().receive();
+            } else {
+                (&mut c.shared.settings, &mut c.shared.events).lock(|settings, events| {
+                    let result =
+                        settings.with_target(|settings| c.local.usb_terminal.poll(settings));
+                    let changed = matches!(result, Ok(true)) || settings.target().rejected;
+                    let accepted = changed && settings.accept();
+                    match result {
+                        Ok(true) if accepted => {
+                            // The settings task may already be pending, which applies the
+                            // change as well.
+                            settings::spawn().ok();
+                            let time = Systick::now().ticks();
+                            settings.modify(time);
+                            events.push(time, Event::Settings);
+                        }
+                        Ok(_) => {}
+                        Err(_) => log::warn!("USB terminal error"),
+                    }
+                });
+            }
+
+            // Execute commands from the USB terminal.
+            let platform = c.local.usb_terminal.platform_mut();
+            if let Some(request) = platform.pending.take() {
+                let mut response = Response::new();
+                let result = execute(&mut c.shared, platform, &request, &mut response);
+                let interface = c.local.usb_terminal.interface_mut();
+                match result {
+                    Ok(()) => writeln!(interface, "{response}"),
+                    Err(e) => writeln!(interface, "Command failed: {e:?}"),
+                }
+                .ok();
+            }
+
+            // Write the exported configuration to the USB terminal in parts that fit the USB
+            // buffer.
+            if let Some(mut export) = c.local.usb_terminal.platform_mut().export.take() {
+                let interface = c.local.usb_terminal.interface_mut();
+                let result = c.shared.settings.lock(|settings| {
+                    export.write::<_, 8>(&*settings, config::CHUNK_SIZE, interface)
+                });
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => c.local.usb_terminal.platform_mut().export = Some(export),
+                    Err(e) => {
+                        writeln!(interface, "Export failed: {e:?}").ok();
+                    }
+                }
+            }
+
+            // Execute commands received over MQTT.
+            while let Some((request, reply)) =
+                c.shared.network.lock(|net| net.telemetry.take_request())
+            {
+                let command = request.as_ref().ok().map(|request| request.command);
+                if command == Some(Command::Export) {
+                    // The document does not fit into a command response. It is serialized
+                    // directly into the MQTT buffer.
+                    // A copy of the settings is serialized to keep the settings lock short.
+                    if let Some(reply) = reply {
+                        let settings = c.shared.settings.lock(|settings| settings.clone());
+                        c.shared.network.lock(|net| {
+                            net.telemetry.reply_with(&reply, |buffer| {
+                                let mut cursor = config::Cursor::new(buffer);
+                                config::Export::default()
+                                    .write::<_, 8>(&settings, usize::MAX, &mut cursor)
+                                    .map(|_| cursor.position())
+                            })
+                        });
+                    }
+                    continue;
+                }
+                let mut response = Response::new();
+                let platform = c.local.usb_terminal.platform_mut();
+                let result =
+                    request.and_then(|r| execute(&mut c.shared, platform, &r, &mut response));
+                if let Some(reply) = reply {
+                    c.shared
+                        .network
+                        .lock(|net| net.telemetry.reply(&reply, result, &response));
+                }
+            }
+
+            // Persist events. This is done here as the USB terminal owns the flash.
+            c.shared.events.lock(|events| {
+                if events.is_pending() {
+                    events.persist(&mut c.local.usb_terminal.platform_mut().storage);
                 }
             });
 
+            // Lend the configuration document buffer to the MQTT client until the next poll unless
+            // a document is being received from the USB terminal.
+            let platform = c.local.usb_terminal.platform_mut();
+            if !platform.raw {
+                if let Some(document) = platform.import.take() {
+                    c.shared
+                        .network
+                        .lock(|net| net.telemetry.lend_document(document));
+                }
+            }
+
+            // Save the settings once the autosave delay elapsed after the last change.
+            let unsaved = c
+                .shared
+                .settings
+                .lock(|settings| settings.autosave(Systick::now().ticks()));
+            if let Some(unsaved) = unsaved {
+                let platform = c.local.usb_terminal.platform_mut();
+                if let Ok(count) = unsaved.persist(platform, true, |_| {}) {
+                    log::info!("Autosaved {count} settings");
+                }
+            }
+
             Systick::delay(10.millis()).await;
         }
     }
 
-    #[task(priority = 1, shared=[network])]
+    /// Execute a command.
+    ///
+    /// # Args
+    /// * `shared` - The shared resources of the `usb` task.
+    /// * `platform` - The settings platform owning the flash.
+    /// * `request` - The command request.
+    /// * `response` - The response text.
+    fn execute(
+        shared: &mut usb::SharedResources,
+        platform: &mut crate::settings::SerialSettingsPlatform<Settings, 8>,
+        request: &Request,
+        response: &mut Response,
+    ) -> Result<(), command::Error> {
+        match request.command {
+            Command::Acknowledge => {
+                let changed = shared.interlock.lock(|interlock| {
+                    OutputChannelIdx::iter().fold(false, |changed, ch| {
+                        interlock.set(Source::Watchdog, ch, Constraint::default()) | changed
+                    })
+                });
+                if changed {
+                    settings::spawn().ok();
+                }
+                shared.alarm_state.lock(|state| state.acknowledge());
+                shared
+                    .events
+                    .lock(|events| events.push(Systick::now().ticks(), Event::Acknowledge));
+                write!(response, "Acknowledged")?;
+            }
+            Command::ResetFault => {
+                let channels = request.channels()?;
+                let changed = (&mut shared.interlock, &mut shared.fault, &mut shared.events).lock(
+                    |interlock, fault, events| {
+                        OutputChannelIdx::iter()
+                            .filter(|ch| channels.contains(&(*ch as usize)))
+                            .fold(false, |changed, ch| {
+                                events.push(Systick::now().ticks(), Event::FaultReset(ch as _));
+                                fault.reset(interlock, ch) | changed
+                            })
+                    },
+                );
+                if changed {
+                    settings::spawn().ok();
+                }
+                write!(response, "Faults cleared")?;
+            }
+            Command::Identify => {
+                if request.argument.is_empty() {
+                    return Err(command::Error::Argument);
+                }
+                let ch = request.channels()?.start;
+                shared.settings.lock(|settings| {
+                    let identification = &settings.thermostat_eem.output[ch].identification;
+                    if *identification.excitation == Excitation::None {
+                        return Err(command::Error::Invalid("identification/excitation: none"));
+                    }
+                    Ok(())
+                })?;
+                shared
+                    .identify
+                    .lock(|identify| identify[ch] = Trigger::Start);
+                write!(response, "Identification started on channel {ch}")?;
+            }
+            Command::IdentifyStop => {
+                let channels = request.channels()?;
+                shared.identify.lock(|identify| {
+                    for trigger in identify[channels].iter_mut() {
+                        *trigger = Trigger::Stop;
+                    }
+                });
+                write!(response, "Identification stopped")?;
+            }
+            Command::Events => {
+                let start = match request.argument.as_str() {
+                    "" => 0,
+                    start => start.parse().map_err(|_| command::Error::Argument)?,
+                };
+                event::read(&mut platform.storage, start, response)?;
+            }
+            Command::ClearEvents => {
+                event::clear(&mut platform.storage)?;
+                write!(response, "Events cleared")?;
+            }
+            Command::Save => {
+                // Access the flash outside of the settings lock.
+                let (snapshot, modified) = shared
+                    .settings
+                    .lock(|settings| (settings.thermostat_eem.clone(), settings.modified.take()));
+                let count = snapshot.persist(platform, true, |_| {}).inspect_err(|_| {
+                    // Keep the changes recorded as unsaved.
+                    shared.settings.lock(|settings| {
+                        settings.modified = settings.modified.or(modified);
+                    });
+                })?;
+                write!(response, "Saved {count} settings")?;
+            }
+            Command::Unsaved => {
+                let snapshot = shared
+                    .settings
+                    .lock(|settings| settings.thermostat_eem.clone());
+                let mut truncated = false;
+                let count = snapshot.persist(platform, false, |path| {
+                    // Keep room for the count.
+                    if response.len() + path.len() + 32 < response.capacity() {
+                        writeln!(response, "{path}").ok();
+                    } else {
+                        truncated = true;
+                    }
+                })?;
+                if truncated {
+                    writeln!(response, "...")?;
+                }
+                write!(response, "{count} unsaved")?;
+            }
+            Command::ProfileSave => {
+                let name = profile::name(&request.argument)?;
+                // Access the flash outside of the settings lock.
+                let snapshot = shared
+                    .settings
+                    .lock(|settings| settings.thermostat_eem.clone());
+                profile::save::<_, _, 8>(platform, &name, &snapshot)?;
+                write!(response, "Profile `{name}` saved")?;
+            }
+            Command::ProfileLoad => {
+                let name = profile::name(&request.argument)?;
+                // Load into a copy of the settings (the staged settings during a transaction)
+                // outside of the settings lock and only swap it in under the lock.
+                let mut loaded = shared.settings.lock(|settings| settings.target().clone());
+                loaded.load_profile(platform, &name)?;
+                let time = Systick::now().ticks();
+                shared.settings.lock(|settings| {
+                    *settings.target() = loaded;
+                    settings.modify(time);
+                });
+                settings::spawn().ok();
+                shared
+                    .events
+                    .lock(|events| events.push(time, Event::Settings));
+                write!(response, "Profile `{name}` loaded")?;
+            }
+            Command::ProfileList => {
+                let default = profile::default(platform)?;
+                let profiles = profile::list(platform)?;
+                for name in profiles.iter() {
+                    write!(response, "{name}")?;
+                    if default.as_ref() == Some(name) {
+                        write!(response, " (default)")?;
+                    }
+                    writeln!(response)?;
+                }
+                if profiles.is_empty() {
+                    write!(response, "No profiles")?;
+                }
+            }
+            Command::ProfileDelete => {
+                let name = profile::name(&request.argument)?;
+                profile::delete::<_, ThermostatEem, 8>(platform, &name)?;
+                write!(response, "Profile `{name}` deleted")?;
+            }
+            Command::ProfileDefault => match request.argument.as_str() {
+                "" => {
+                    profile::set_default(platform, None)?;
+                    write!(response, "Boot default profile cleared")?;
+                }
+                name => {
+                    let name = profile::name(name)?;
+                    profile::set_default(platform, Some(&name))?;
+                    write!(response, "Boot default profile `{name}`")?;
+                }
+            },
+            Command::Begin => {
+                shared
+                    .settings
+                    .lock(|settings| settings.transaction.begin(&settings.thermostat_eem))?;
+                write!(response, "Transaction started")?;
+            }
+            Command::Commit => {
+                let time = Systick::now().ticks();
+                shared.settings.lock(|settings| {
+                    settings.commit()?;
+                    settings.modify(time);
+                    Ok::<_, command::Error>(())
+                })?;
+                settings::spawn().ok();
+                shared
+                    .events
+                    .lock(|events| events.push(time, Event::Settings));
+                write!(response, "Committed")?;
+            }
+            Command::Abort => {
+                shared
+                    .settings
+                    .lock(|settings| settings.transaction.abort())?;
+                write!(response, "Aborted")?;
+            }
+            Command::Diff => {
+                shared.settings.lock(|settings| {
+                    let staged = settings
+                        .transaction
+                        .staged()
+                        .ok_or(command::Error::Transaction)?;
+                    transaction::diff::<_, 8>(&settings.thermostat_eem, staged, response)
+                })?;
+            }
+            Command::Export => {
+                // The document is written to the USB terminal by the `usb` task. Over MQTT it is
+                // the response (see `usb`).
+                platform.export = Some(config::Export::default());
+            }
+            Command::Import => {
+                let document = platform.import.take().ok_or(command::Error::Busy)?;
+                // Import into a copy of the settings outside of the settings lock and only swap
+                // it in under the lock.
+                let result = shared
+                    .settings
+                    .lock(|settings| settings.for_import())
+                    .and_then(|mut imported| {
+                        let count = imported.import(platform, &document[..])?;
+                        Ok((imported, count))
+                    });
+                // The document is consumed.
+                document.clear();
+                platform.import = Some(document);
+                let (imported, count) = result?;
+                let time = Systick::now().ticks();
+                shared.settings.lock(|settings| {
+                    settings.thermostat_eem = imported.thermostat_eem;
+                    settings.net = imported.net;
+                    settings.modify(time);
+                });
+                settings::spawn().ok();
+                shared
+                    .events
+                    .lock(|events| events.push(time, Event::Settings));
+                write!(response, "Imported {count} settings")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the Ethernet link and applies the connection-loss policy.
+    #[task(priority = 1, local=[connection], shared=[network, settings, interlock, events])]
     async fn ethernet_link(mut c: ethernet_link::Context) {
+        // Link handling period in seconds
+        const PERIOD: u32 = 1;
         loop {
-            c.shared
-                .network
-                .lock(|network| network.processor.handle_link());
-            Systick::delay(1.secs()).await;
+            let connected = c.shared.network.lock(|network| {
+                network.processor.handle_link() && network.telemetry.is_connected()
+            });
+            let monitor = &mut c.local.connection;
+            let was_connected = monitor.status().connected;
+            let changed =
+                (&mut c.shared.settings, &mut c.shared.interlock).lock(|settings, interlock| {
+                    let connection = settings.thermostat_eem.connection;
+                    let policy = monitor.update(&connection, connected, PERIOD as _);
+                    let state = match policy {
+                        Policy::Continue | Policy::Safe => None,
+                        Policy::Hold => Some(State::Hold),
+                        Policy::Off => Some(State::Off),
+                    };
+                    OutputChannelIdx::iter().fold(false, |changed, ch| {
+                        let idx = ch as usize;
+                        settings.overrides[idx].setpoint =
+                            (policy == Policy::Safe).then_some(connection.setpoint[idx]);
+                        interlock.set(
+                            Source::Connection,
+                            ch,
+                            Constraint {
+                                state,
+                                ..Default::default()
+                            },
+                        ) | changed
+                    })
+                });
+            if changed {
+                settings::spawn().ok();
+            }
+            if monitor.status().connected != was_connected {
+                c.shared.events.lock(|events| {
+                    events.push(Systick::now().ticks(), Event::Connection(connected))
+                });
+            }
+            if monitor.pending()
+                && c.shared
+                    .network
+                    .lock(|network| network.telemetry.publish_connection(&monitor.status()))
+            {
+                monitor.published();
+            }
+            Systick::delay(PERIOD.secs()).await;
         }
     }
 
+    #[task(binds = TIM2, priority = 1, shared=[fan])]
+    fn tacho(mut c: tacho::Context) {
+        c.shared.fan.lock(|fan| fan.handle_interrupt());
+    }
+
     #[task(binds = ETH, priority = 1)]
     fn eth(_: eth::Context) {
         unsafe { hal::ethernet::interrupt_handler() }
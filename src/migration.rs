@@ -0,0 +1,298 @@
+//! # Thermostat_EEM settings schema migration
+//!
+//! Settings are persisted per path. When a path is renamed or its type changes, the persisted
+//! value no longer loads. To carry settings across firmware upgrades, the settings schema version
+//! is stored in flash under `schema`. At boot, the entries of the [MIGRATIONS] table newer than
+//! the stored version are applied before the settings are loaded: the value of the old path is
+//! moved (and optionally converted) to the new path or dropped.
+//!
+//! The keys that were migrated or dropped, the persisted keys that fail to load and those that
+//! are unknown to the current settings tree are reported in the log and by the USB terminal
+//! `platform service` command.
+//!
+//! When renaming a path or changing its type, increment [VERSION] and add the corresponding
+//! entries to [MIGRATIONS]. New paths need no entry as their defaults apply. For example, moving
+//! `telemetry_period` (in seconds as `f32`) to `telemetry/period` (in milliseconds as `u32`)
+//! would be:
+//!
+//! ```ignore
+//! Migration {
+//!     version: 2,
+//!     from: "/thermostat_eem/telemetry_period",
+//!     to: Some("/thermostat_eem/telemetry/period"),
+//!     convert: Some(|old, new| {
+//!         let period: f32 = ::postcard::from_bytes(old).ok()?;
+//!         let period = (period * 1e3).round() as u32;
+//!         Some(::postcard::to_slice(&period, new).ok()?.len())
+//!     }),
+//! }
+//! ```
+//!
+//! Version `1` introduced the schema version. At the same time the event log took over the end
+//! of the settings flash region; the settings are moved out of it before the migration (see
+//! [crate::settings::upgrade_layout]).
+
+use core::fmt::Write;
+use embassy_futures::block_on;
+use heapless::{String, Vec};
+use miniconf::{Path, TreeKey};
+use sequential_storage::{
+    cache::NoCache,
+    map::{fetch_all_items, fetch_item, store_item},
+};
+
+use crate::{hardware::flash::Flash, settings::SettingsKey};
+
+/// Current settings schema version.
+///
+/// Version `0` denotes settings persisted before schema versioning.
+pub const VERSION: u32 = 1;
+
+/// A settings schema migration.
+pub struct Migration {
+    /// The schema version introducing the change.
+    pub version: u32,
+    /// The old path.
+    ///
+    /// A path ending in `/` denotes all current settings paths below it. This is only supported
+    /// for dropping settings (`to` is `None`).
+    pub from: &'static str,
+    /// The new path. `None` if the setting was removed.
+    pub to: Option<&'static str>,
+    /// Conversion of the postcard serialized value to the new type.
+    ///
+    /// # Args
+    /// * `old` - The old value.
+    /// * `new` - Buffer for the new value.
+    ///
+    /// # Returns
+    /// The length of the new value. `None` if the value can not be converted.
+    pub convert: Option<fn(&[u8], &mut [u8]) -> Option<usize>>,
+}
+
+/// Settings schema migrations in increasing version order.
+pub const MIGRATIONS: &[Migration] = &[
+    // The input channel configuration is derived from the hardware at boot and no longer
+    // persisted. Values persisted by earlier firmware were overwritten at boot anyway.
+    Migration {
+        version: 1,
+        from: "/thermostat_eem/input/",
+        to: None,
+        convert: None,
+    },
+];
+
+/// Flash key of the settings schema version.
+const VERSION_KEY: &[u8] = b"schema";
+
+/// Outcome for a persisted key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Moved to a new path.
+    Migrated,
+    /// Removed or failed to load.
+    Dropped,
+    /// Not part of the settings tree.
+    Unknown,
+}
+
+/// Migration report.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The stored schema version. `None` if none was stored.
+    pub version: Option<u32>,
+    /// The reported keys.
+    pub keys: Vec<(Outcome, String<128>), 16>,
+    /// Number of reported keys that did not fit.
+    pub omitted: usize,
+}
+
+impl Report {
+    /// Report a key.
+    pub fn push(&mut self, outcome: Outcome, key: &str) {
+        log::warn!("Settings `{key}`: {outcome:?}");
+        if self.keys.iter().any(|(o, k)| *o == outcome && k == key) {
+            return;
+        }
+        let mut k = String::new();
+        k.push_str(key).ok();
+        if self.keys.push((outcome, k)).is_err() {
+            self.omitted += 1;
+        }
+    }
+}
+
+impl core::fmt::Display for Report {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "{version}")?,
+            None => write!(f, "none")?,
+        }
+        write!(f, " -> {VERSION}")?;
+        for (outcome, key) in self.keys.iter() {
+            write!(f, ", {outcome:?} `{key}`")?;
+        }
+        if self.omitted > 0 {
+            write!(f, ", {} more", self.omitted)?;
+        }
+        Ok(())
+    }
+}
+
+fn settings_key(key: &[u8]) -> Option<SettingsKey> {
+    Some(SettingsKey(Vec::from_slice(key).ok()?))
+}
+
+/// Apply a migration to a persisted key.
+///
+/// The value of the key is moved (and optionally converted) to the new path or dropped.
+fn apply(storage: &mut Flash, from: &str, migration: &Migration, report: &mut Report) {
+    let range = storage.range();
+    let mut buffer = [0u8; 512];
+    let mut value = [0u8; 256];
+    let Some(old) = settings_key(from.as_bytes()) else {
+        return;
+    };
+    let len = match block_on(fetch_item::<_, &[u8], _>(
+        storage,
+        range.clone(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &old,
+    )) {
+        Ok(Some(v)) if !v.is_empty() => match migration.convert {
+            Some(convert) => convert(v, &mut value),
+            None => value.get_mut(..v.len()).map(|value| {
+                value.copy_from_slice(v);
+                v.len()
+            }),
+        },
+        _ => return,
+    };
+    let new = migration.to.zip(len).and_then(|(to, len)| {
+        block_on(store_item(
+            storage,
+            range.clone(),
+            &mut NoCache::new(),
+            &mut buffer,
+            &settings_key(to.as_bytes())?,
+            &&value[..len],
+        ))
+        .ok()
+    });
+    // Clear the old key.
+    block_on(store_item(
+        storage,
+        range.clone(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &old,
+        &&b""[..],
+    ))
+    .ok();
+    match new {
+        Some(()) => {
+            let mut key: String<128> = String::new();
+            write!(key, "{} -> {}", from, migration.to.unwrap_or("")).ok();
+            report.push(Outcome::Migrated, &key);
+        }
+        None => report.push(Outcome::Dropped, from),
+    }
+}
+
+/// Migrate the persisted settings to the current schema version.
+///
+/// This must be called before the settings are loaded.
+///
+/// # Returns
+/// The migration report.
+pub fn migrate<C: TreeKey, const Y: usize>(storage: &mut Flash) -> Report {
+    let range = storage.range();
+    let mut report = Report::default();
+    let mut buffer = [0u8; 512];
+    let mut value = [0u8; 256];
+
+    let version_key = settings_key(VERSION_KEY).unwrap();
+    let stored: Result<Option<&[u8]>, _> = block_on(fetch_item(
+        storage,
+        range.clone(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &version_key,
+    ));
+    report.version = stored
+        .ok()
+        .flatten()
+        .and_then(|v| v.try_into().ok())
+        .map(u32::from_le_bytes);
+
+    let from = report.version.unwrap_or(0);
+    for migration in MIGRATIONS.iter().filter(|m| m.version > from) {
+        if migration.from.ends_with('/') {
+            for (path, _node) in C::nodes::<Path<String<128>, '/'>, Y>().flatten() {
+                if path.as_str().starts_with(migration.from) {
+                    apply(storage, path.as_str(), migration, &mut report);
+                }
+            }
+        } else {
+            apply(storage, migration.from, migration, &mut report);
+        }
+    }
+
+    if report.version != Some(VERSION) {
+        log::info!("Settings schema version: {:?} -> {VERSION}", report.version);
+        if let Err(e) = block_on(store_item(
+            storage,
+            range.clone(),
+            &mut NoCache::new(),
+            &mut buffer,
+            &version_key,
+            &&VERSION.to_le_bytes()[..],
+        )) {
+            log::warn!("Failed to store settings schema version: {e:?}");
+        }
+    }
+
+    // Find persisted settings keys that are not part of the settings tree. Keys not starting
+    // with `/` are application data (e.g. boot count, profiles).
+    let mut unknown: Vec<SettingsKey, 16> = Vec::new();
+    let mut cache = NoCache::new();
+    if let Ok(mut items) = block_on(fetch_all_items::<SettingsKey, _, _>(
+        storage,
+        range.clone(),
+        &mut cache,
+        &mut buffer,
+    )) {
+        while let Ok(Some((key, item))) = block_on(items.next::<SettingsKey, &[u8]>(&mut value)) {
+            if item.is_empty()
+                || key.0.first() != Some(&b'/')
+                || unknown.contains(&key)
+                || C::nodes::<Path<String<128>, '/'>, Y>()
+                    .flatten()
+                    .any(|(path, _node)| path.as_str().as_bytes() == &key.0[..])
+            {
+                continue;
+            }
+            if unknown.push(key).is_err() {
+                report.omitted += 1;
+            }
+        }
+    }
+    for key in unknown.iter() {
+        // Items may be stale. Only report keys that are currently set.
+        let current: Result<Option<&[u8]>, _> = block_on(fetch_item(
+            storage,
+            range.clone(),
+            &mut NoCache::new(),
+            &mut buffer,
+            key,
+        ));
+        if current.is_ok_and(|v| v.is_some_and(|v| !v.is_empty())) {
+            report.push(
+                Outcome::Unknown,
+                core::str::from_utf8(&key.0).unwrap_or("?"),
+            );
+        }
+    }
+    report
+}
@@ -83,6 +83,11 @@ pub enum StreamFormat {
     Fls = 2,
 
     ThermostatEem = 3,
+
+    /// Thermostat-EEM system identification data. Streamed while an identification run is active
+    /// on any output channel. Each batch contains the input temperatures, the output currents,
+    /// the loop inputs and the excitations (all `f32`).
+    ThermostatEemIdentification = 4,
 }
 
 /// Represents the destination for the UDP stream to send data to.
@@ -112,6 +117,19 @@ impl Serialize for StreamTarget {
     }
 }
 
+impl StreamTarget {
+    /// Validate the stream target.
+    ///
+    /// The target is either unspecified (stream disabled) or has a non-zero port.
+    pub fn validate(&self) -> crate::validate::Result {
+        if !self.0.ip().is_unspecified() && self.0.port() == 0 {
+            Err("stream: port is zero")
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl core::str::FromStr for StreamTarget {
     type Err = &'static str;
 
@@ -236,6 +254,25 @@ impl FrameGenerator {
         self.format = format.into();
     }
 
+    /// Change the format of the stream.
+    ///
+    /// # Note:
+    /// A partially filled frame of a different format is enqueued for transmission first.
+    ///
+    /// # Args
+    /// * `format` - The desired format of the stream.
+    pub fn set_format(&mut self, format: impl Into<u8>) {
+        let format = format.into();
+        if format != self.format {
+            if let Some(frame) = self.current_frame.take() {
+                // Note(unwrap): The queue is designed to be at least as large as the frame buffer
+                // count, so this enqueue should always succeed.
+                self.queue.enqueue(frame).unwrap();
+            }
+            self.format = format;
+        }
+    }
+
     /// Add a batch to the current stream frame.
     ///
     /// # Args
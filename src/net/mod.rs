@@ -14,6 +14,7 @@ pub mod network_processor;
 pub mod telemetry;
 
 use crate::{
+    config::DOCUMENT_SIZE,
     hardware::{metadata::ApplicationMetadata, EthernetPhy, NetworkManager, NetworkStack},
     settings::NetSettings,
     SystemTimer,
@@ -24,19 +25,19 @@ use telemetry::TelemetryClient;
 
 use core::fmt::Write;
 use heapless::String;
-use miniconf::{Leaf, Tree, TreeDeserializeOwned, TreeKey, TreeSerialize};
+use miniconf::{TreeDeserializeOwned, TreeKey, TreeSerialize};
 
 pub type NetworkReference = smoltcp_nal::shared::NetworkStackProxy<'static, NetworkStack>;
 
 pub struct MqttStorage {
-    telemetry: [u8; 2048],
+    telemetry: [u8; 1024 + 2 * DOCUMENT_SIZE],
     settings: [u8; 1024],
 }
 
 impl Default for MqttStorage {
     fn default() -> Self {
         Self {
-            telemetry: [0u8; 2048],
+            telemetry: [0u8; 1024 + 2 * DOCUMENT_SIZE],
             settings: [0u8; 1024],
         }
     }
@@ -136,9 +137,9 @@ where
                 stack_manager.acquire_stack(),
                 clock,
                 minimq::ConfigBuilder::new(named_broker, &mut store.telemetry)
-                    // The telemetry client doesn't receive any messages except MQTT control packets.
-                    // As such, we don't need much of the buffer for RX.
-                    .rx_buffer(minimq::config::BufferConfig::Maximum(100))
+                    // The telemetry client only receives short command requests and configuration
+                    // documents besides MQTT control packets.
+                    .rx_buffer(minimq::config::BufferConfig::Maximum(512 + DOCUMENT_SIZE))
                     .session_state(minimq::config::BufferConfig::Maximum(0))
                     .client_id(&get_client_id(&net_settings.id, "tlm"))
                     .unwrap(),
@@ -234,63 +235,3 @@ pub fn get_device_prefix(app: &str, id: &str) -> String<128> {
 
     prefix
 }
-
-/// Miniconf settings for the MQTT alarm.
-/// The alarm simply publishes "false" onto its `target` as long as all the channels are
-/// within their `temperature_limits`` (aka logical OR of all channels).
-/// Otherwise it publishes "true" (aka true, there is an alarm).
-///
-/// The publishing interval is given by `period_ms`.
-///
-/// The alarm is non-latching. If alarm was "true" for a while and the temperatures come within
-/// limits again, alarm will be "false" again.
-#[derive(Clone, Debug, Tree)]
-pub struct Alarm {
-    /// Set the alarm to armed (true) or disarmed (false).
-    /// If the alarm is armed, the device will publish it's alarm state onto the `target`.
-    ///
-    /// # Value
-    /// True to arm, false to disarm.
-    pub armed: Leaf<bool>,
-
-    /// Alarm target.
-    /// The alarm will publish its state (true or false) onto this mqtt path.
-    /// Full path to the desired target. No wildcards.
-    ///
-    /// # Value
-    /// Any string up to 128 characters.
-    pub target: Leaf<String<128>>,
-
-    /// Alarm period in milliseconds.
-    /// The alarm will publish its state with this period.
-    ///
-    /// # Value
-    /// f32
-    pub period: Leaf<f32>,
-
-    /// Temperature limits for the alarm.
-    ///
-    /// Array of lower and upper limits for the valid temperature range of the alarm.
-    /// The alarm will be asserted if any of the enabled input channels goes below its minimum or above its maximum temperature.
-    /// The alarm is non latching and clears itself once all channels are in their respective limits.
-    ///
-    /// # Path
-    /// `temperature_limits/<adc>/<channel>`
-    /// * `<adc> := [0, 1, 2, 3]` specifies which adc to configure.
-    /// * `<channel>` specifies which channel of an ADC to configure. Only the enabled channels for the specific ADC are available.
-    ///
-    /// # Value
-    /// `[f32, f32]` or `None`
-    pub temperature_limits: [[Option<[Leaf<f32>; 2]>; 4]; 4],
-}
-
-impl Default for Alarm {
-    fn default() -> Self {
-        Self {
-            armed: false.into(),
-            target: Default::default(),
-            period: 1.0.into(),
-            temperature_limits: Default::default(),
-        }
-    }
-}
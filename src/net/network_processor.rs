@@ -36,7 +36,10 @@ impl NetworkProcessor {
     /// # Note
     /// This may take non-trivial amounts of time to communicate with the PHY. As such, this should
     /// only be called as often as necessary (e.g. once per second or so).
-    pub fn handle_link(&mut self) {
+    ///
+    /// # Returns
+    /// True if the link is up.
+    pub fn handle_link(&mut self) -> bool {
         // If the PHY indicates there's no more ethernet link, reset the DHCP server in the network
         // stack.
         let link_up = self.phy.poll_link();
@@ -54,6 +57,7 @@ impl NetworkProcessor {
             }
             _ => {}
         };
+        link_up
     }
 
     /// Process and update the state of the network.
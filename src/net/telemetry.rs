@@ -10,16 +10,60 @@
 //! sampling frequency. Instead, the raw codes are stored and the telemetry is generated as
 //! required immediately before transmission. This ensures that any slower computation required
 //! for unit conversion can be off-loaded to lower priority tasks.
-use heapless::String;
-use minimq::{DeferredPublication, Publication};
+//!
+//! The telemetry client also receives [crate::command] requests on `<prefix>/command/#` and
+//! publishes the responses. The payload of an `import` request is the configuration document (see
+//! [crate::config]). It is copied into the shared document buffer while that is lent to the
+//! client.
+use heapless::{Deque, String, Vec};
+use minimq::{
+    types::{BinaryData, Properties, TopicFilter, Utf8String},
+    DeferredPublication, Property, Publication,
+};
 use serde::Serialize;
 
 use super::NetworkReference;
-use crate::hardware::{metadata::ApplicationMetadata, SystemTimer};
+use crate::{
+    command::{self, Request},
+    config::Document,
+    hardware::{metadata::ApplicationMetadata, SystemTimer},
+    output_channel::Applied,
+};
 
 /// Default metadata message if formatting errors occur.
 const DEFAULT_METADATA: &str = "{\"message\":\"Truncated: See USB terminal\"}";
 
+/// Response routing of a command request received over MQTT.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    topic: String<128>,
+    correlation_data: Vec<u8, 32>,
+}
+
+impl Reply {
+    /// Extract the response topic and correlation data from the request properties.
+    ///
+    /// # Returns
+    /// `None` if the request does not have a (valid) response topic.
+    fn new(properties: &Properties) -> Option<Self> {
+        let mut topic = None;
+        let mut correlation_data = Vec::new();
+        for property in properties.into_iter().flatten() {
+            match property {
+                Property::ResponseTopic(Utf8String(t)) => topic = t.try_into().ok(),
+                Property::CorrelationData(BinaryData(d)) => {
+                    correlation_data = Vec::from_slice(d).unwrap_or_default()
+                }
+                _ => {}
+            }
+        }
+        topic.map(|topic| Self {
+            topic,
+            correlation_data,
+        })
+    }
+}
+
 /// The telemetry client for reporting telemetry data over MQTT.
 pub struct TelemetryClient {
     mqtt: minimq::Minimq<
@@ -30,7 +74,11 @@ pub struct TelemetryClient {
     >,
     prefix: &'static str,
     meta_published: bool,
+    subscribed: bool,
+    requests: Deque<(Result<Request, command::Error>, Option<Reply>), 4>,
+    document: Option<&'static mut Document>,
     metadata: &'static ApplicationMetadata,
+    applied: Option<[Applied; 4]>,
 }
 
 impl TelemetryClient {
@@ -55,8 +103,12 @@ impl TelemetryClient {
         Self {
             mqtt,
             meta_published: false,
+            subscribed: false,
+            requests: Deque::new(),
+            document: None,
             prefix,
             metadata,
+            applied: None,
         }
     }
 
@@ -84,18 +136,160 @@ impl TelemetryClient {
             .ok();
     }
 
-    /// A secondary functionality tugged onto the telemetry client that publishes onto another
-    /// `alarm_topic`.
-    pub fn publish_alarm(&mut self, alarm_topic: &String<128>, alarm: &bool) {
+    /// A secondary functionality tugged onto the telemetry client that publishes the alarm
+    /// message onto the `alarm_topic` and retained onto `<prefix>/alarm`.
+    ///
+    /// # Returns
+    /// True if the alarm message was published.
+    pub fn publish_alarm<T: Serialize>(&mut self, alarm_topic: &str, alarm: &T) -> bool {
+        let mut topic: String<128> = self.prefix.try_into().unwrap();
+        topic.push_str("/alarm").unwrap();
+
+        let target = alarm_topic.is_empty()
+            || self
+                .mqtt
+                .client()
+                .publish(
+                    DeferredPublication::new(|buf| serde_json_core::to_slice(alarm, buf))
+                        .topic(alarm_topic)
+                        .finish()
+                        .unwrap(),
+                )
+                .map_err(|e| log::error!("Alarm publishing error: {:?}", e))
+                .is_ok();
+        let state = self
+            .mqtt
+            .client()
+            .publish(
+                DeferredPublication::new(|buf| serde_json_core::to_slice(alarm, buf))
+                    .topic(&topic)
+                    .retain()
+                    .finish()
+                    .unwrap(),
+            )
+            .map_err(|e| log::error!("Alarm state publishing error: {:?}", e))
+            .is_ok();
+        target && state
+    }
+
+    /// Publish the connection status retained onto `<prefix>/connection`.
+    ///
+    /// # Returns
+    /// True if the status was published.
+    pub fn publish_connection<T: Serialize>(&mut self, status: &T) -> bool {
+        let mut topic: String<128> = self.prefix.try_into().unwrap();
+        topic.push_str("/connection").unwrap();
+
         self.mqtt
             .client()
             .publish(
-                minimq::DeferredPublication::new(|buf| serde_json_core::to_slice(alarm, buf))
-                    .topic(alarm_topic)
+                DeferredPublication::new(|buf| serde_json_core::to_slice(status, buf))
+                    .topic(&topic)
+                    .retain()
                     .finish()
                     .unwrap(),
             )
-            .map_err(|e| log::error!("Alarm publishing error: {:?}", e))
+            .map_err(|e| log::error!("Connection status publishing error: {:?}", e))
+            .is_ok()
+    }
+
+    /// Publish the effectively applied output channel settings retained onto `<prefix>/applied`.
+    ///
+    /// # Note
+    /// The values are published once the client is connected.
+    pub fn publish_applied(&mut self, applied: [Applied; 4]) {
+        self.applied = Some(applied);
+    }
+
+    /// Whether the client is connected to the broker.
+    pub fn is_connected(&mut self) -> bool {
+        self.mqtt.client().is_connected()
+    }
+
+    /// Take the next command request received over MQTT.
+    pub fn take_request(&mut self) -> Option<(Result<Request, command::Error>, Option<Reply>)> {
+        self.requests.pop_front()
+    }
+
+    /// Lend the configuration document buffer to receive `import` requests.
+    pub fn lend_document(&mut self, document: &'static mut Document) {
+        self.document = Some(document);
+    }
+
+    /// Take back the configuration document buffer. It holds the document of a pending `import`
+    /// request, if any.
+    pub fn take_document(&mut self) -> Option<&'static mut Document> {
+        self.document.take()
+    }
+
+    /// Publish a command response serialized directly into the MQTT buffer.
+    ///
+    /// If serialization fails, an error response is published instead.
+    ///
+    /// # Args
+    /// * `reply` - The response routing of the request.
+    /// * `serialize` - Serializes the response into the buffer and returns its length.
+    pub fn reply_with(
+        &mut self,
+        reply: &Reply,
+        serialize: impl FnOnce(&mut [u8]) -> Result<usize, command::Error>,
+    ) {
+        let properties = [
+            Property::UserProperty(Utf8String("code"), Utf8String("Ok")),
+            Property::CorrelationData(BinaryData(&reply.correlation_data)),
+        ];
+        let properties = if reply.correlation_data.is_empty() {
+            &properties[..1]
+        } else {
+            &properties[..]
+        };
+        let result = self.mqtt.client().publish(
+            DeferredPublication::new(serialize)
+                .topic(&reply.topic)
+                .properties(properties)
+                .finish()
+                .unwrap(),
+        );
+        if let Err(e) = result {
+            log::error!("Command response publishing error: {:?}", e);
+            self.reply(reply, Err(command::Error::Truncated), "");
+        }
+    }
+
+    /// Publish a command response.
+    ///
+    /// # Args
+    /// * `reply` - The response routing of the request.
+    /// * `result` - The command result.
+    /// * `response` - The response text.
+    pub fn reply(&mut self, reply: &Reply, result: Result<(), command::Error>, response: &str) {
+        let mut error: String<64> = String::new();
+        let (code, payload) = match result {
+            Ok(()) => ("Ok", response),
+            Err(e) => {
+                core::fmt::Write::write_fmt(&mut error, format_args!("{e:?}")).ok();
+                ("Error", error.as_str())
+            }
+        };
+        let properties = [
+            Property::UserProperty(Utf8String("code"), Utf8String(code)),
+            Property::CorrelationData(BinaryData(&reply.correlation_data)),
+        ];
+        let properties = if reply.correlation_data.is_empty() {
+            &properties[..1]
+        } else {
+            &properties[..]
+        };
+        self.mqtt
+            .client()
+            .publish(
+                Publication::new(payload.as_bytes())
+                    .topic(&reply.topic)
+                    .properties(properties)
+                    .finish()
+                    .unwrap(),
+            )
+            .map_err(|e| log::error!("Command response publishing error: {:?}", e))
             .ok();
     }
 
@@ -106,7 +300,39 @@ impl TelemetryClient {
     /// and outgoing messages. Without this, the client will never connect to the broker. This
     /// should be called regularly.
     pub fn update(&mut self) {
-        match self.mqtt.poll(|_client, _topic, _message, _properties| {}) {
+        let Self {
+            ref mut mqtt,
+            ref mut requests,
+            ref mut document,
+            prefix,
+            ..
+        } = self;
+        match mqtt.poll(|_client, topic, message, properties| {
+            let Some(command) = topic
+                .strip_prefix(*prefix)
+                .and_then(|t| t.strip_prefix("/command/"))
+            else {
+                return;
+            };
+            let request = if command == "import" {
+                // The document does not fit into a command argument.
+                match document.as_deref_mut() {
+                    Some(document) if document.is_empty() => document
+                        .extend_from_slice(message)
+                        .map_err(|_| command::Error::Argument)
+                        .and_then(|()| Request::new(command, b"")),
+                    _ => Err(command::Error::Busy),
+                }
+            } else {
+                Request::new(command, message)
+            };
+            if requests
+                .push_back((request, Reply::new(properties)))
+                .is_err()
+            {
+                log::warn!("Command queue overflow");
+            }
+        }) {
             Err(minimq::Error::Network(smoltcp_nal::NetworkError::TcpConnectionFailure(
                 smoltcp_nal::smoltcp::socket::tcp::ConnectError::Unaddressable,
             ))) => {}
@@ -117,9 +343,20 @@ impl TelemetryClient {
 
         if !self.mqtt.client().is_connected() {
             self.meta_published = false;
+            self.subscribed = false;
             return;
         }
 
+        if !self.subscribed {
+            let mut topic: String<128> = self.prefix.try_into().unwrap();
+            topic.push_str("/command/#").unwrap();
+            self.subscribed = self
+                .mqtt
+                .client()
+                .subscribe(&[TopicFilter::new(&topic)], &[])
+                .is_ok();
+        }
+
         // Publish application metadata
         if !self.meta_published && self.mqtt.client().can_publish(minimq::QoS::AtMostOnce) {
             let Self {
@@ -155,5 +392,27 @@ impl TelemetryClient {
 
             self.meta_published = true;
         }
+
+        // Publish the applied settings
+        if let Some(applied) = self.applied {
+            let mut topic: String<128> = self.prefix.try_into().unwrap();
+            topic.push_str("/applied").unwrap();
+
+            if self
+                .mqtt
+                .client()
+                .publish(
+                    DeferredPublication::new(|buf| serde_json_core::to_slice(&applied, buf))
+                        .topic(&topic)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                )
+                .map_err(|e| log::error!("Applied settings publishing error: {:?}", e))
+                .is_ok()
+            {
+                self.applied = None;
+            }
+        }
     }
 }
@@ -1,11 +1,17 @@
 //! # Thermostat_EEM IIR wrapper.
 //!
 
-use crate::{hardware::pwm::Pwm, DacCode};
+use crate::{
+    electrical::Electrical, fault::Code, hardware::pwm::Pwm, identification::Identification,
+    validate, DacCode,
+};
 use idsp::iir;
 use miniconf::{Leaf, Tree};
 use num_traits::Float;
 
+/// Loop sample period in seconds: ADC sample rate (ODR) including zero-order-holds.
+pub const SAMPLE_PERIOD: f64 = 1.0 / 1007.0;
+
 #[derive(Copy, Clone, Debug, Tree)]
 pub struct Pid {
     /// Integral gain
@@ -59,11 +65,21 @@ impl Default for Pid {
     }
 }
 
+impl Pid {
+    /// Validate the PID settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::range([*self.min, *self.max], "pid: min > max")?;
+        iir::Biquad::<f64>::try_from(*self)
+            .map(|_| ())
+            .map_err(|_| "pid: invalid gains or limits")
+    }
+}
+
 impl TryFrom<Pid> for iir::Biquad<f64> {
     type Error = iir::PidError;
     fn try_from(value: Pid) -> Result<Self, Self::Error> {
         let mut biquad: iir::Biquad<f64> = iir::Pid::<f64>::default()
-            .period(1.0 / 1007.0) // ADC sample rate (ODR) including zero-oder-holds
+            .period(SAMPLE_PERIOD)
             .gain(iir::Action::Ki, value.ki.copysign(*value.kp) as _)
             .gain(iir::Action::Kp, *value.kp as _)
             .gain(iir::Action::Kd, value.kd.copysign(*value.kp) as _)
@@ -103,7 +119,16 @@ impl TryFrom<Pid> for iir::Biquad<f64> {
 }
 
 #[derive(
-    Copy, Clone, Default, Debug, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize,
+    Copy,
+    Clone,
+    Default,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
 )]
 pub enum State {
     /// Active TEC driver and Biquad/PID.
@@ -115,6 +140,77 @@ pub enum State {
     Off,
 }
 
+/// Feed-forward path parameters.
+///
+/// A weighted sum of the input channel temperatures and an external value is fed through a
+/// separate PID/Biquad. Its output is added to the output of the main loop before clamping.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct FeedForward {
+    /// Thermostat input channel weights for the feed-forward path.
+    /// In contrast to the [OutputChannel] weights these are not normalized.
+    pub weights: Leaf<[[f32; 4]; 4]>,
+
+    /// External feed-forward value (e.g. supplied via MQTT). It is added to the weighted sum
+    /// of the inputs.
+    ///
+    /// Units: input
+    pub value: Leaf<f32>,
+
+    /// Feed-forward filter parameters.
+    ///
+    /// A plain gain is configured through `kp` alone. The `setpoint` is the input at which
+    /// the feed-forward output is zero.
+    pub pid: Pid,
+
+    #[tree(skip)]
+    pub iir: iir::Biquad<f64>,
+}
+
+impl Default for FeedForward {
+    fn default() -> Self {
+        Self {
+            weights: Default::default(),
+            value: 0.0.into(),
+            pid: Default::default(),
+            iir: Default::default(),
+        }
+    }
+}
+
+impl FeedForward {
+    /// compute the feed-forward input, iir state and return the feed-forward output
+    pub fn update(&self, temperatures: &[[f64; 4]; 4], iir_state: &mut [f64; 4]) -> f64 {
+        let input = weighted_sum(temperatures, &self.weights) + *self.value as f64;
+        self.iir.update(iir_state, input)
+    }
+}
+
+/// Check that the weights are finite and zero for disabled input channels.
+fn validate_weights(weights: &[[f32; 4]; 4], enabled: &[[bool; 4]; 4]) -> validate::Result {
+    weights
+        .as_flattened()
+        .iter()
+        .zip(enabled.as_flattened().iter())
+        .try_for_each(|(w, enabled)| {
+            validate::finite(*w, "weights: not finite")?;
+            if *w != 0.0 && !enabled {
+                Err("weights: disabled input channel")
+            } else {
+                Ok(())
+            }
+        })
+}
+
+/// Weighted sum of the input channel temperatures.
+fn weighted_sum(temperatures: &[[f64; 4]; 4], weights: &[[f32; 4]; 4]) -> f64 {
+    temperatures
+        .as_flattened()
+        .iter()
+        .zip(weights.as_flattened().iter())
+        .map(|(t, w)| t * *w as f64)
+        .sum()
+}
+
 #[derive(Copy, Clone, Debug, Tree)]
 pub struct OutputChannel {
     pub state: Leaf<State>,
@@ -139,6 +235,27 @@ pub struct OutputChannel {
     /// The weights will be internally normalized to one (sum of the absolute values)
     /// if they are not all zero.
     pub weights: Leaf<[[f32; 4]; 4]>,
+
+    /// Maximum output current slew rate in A/s.
+    ///
+    /// The change of the output current from one sample to the next is limited accordingly.
+    /// The loop is informed about the limited output (anti-windup).
+    ///
+    /// # Value
+    /// Positive, `inf` or `NaN` (`null` in JSON) to disable.
+    pub slew_rate: Leaf<f32>,
+
+    /// Feed-forward path. Only active in the `On` state.
+    pub feedforward: FeedForward,
+
+    /// System identification settings.
+    pub identification: Identification,
+
+    /// Electrical output control (constant voltage/power modes and power limit).
+    pub electrical: Electrical,
+
+    /// TEC health monitoring settings.
+    pub health: Health,
 }
 
 impl Default for OutputChannel {
@@ -149,37 +266,117 @@ impl Default for OutputChannel {
             pid: Default::default(),
             iir: Default::default(),
             weights: Default::default(),
+            slew_rate: f32::INFINITY.into(),
+            feedforward: Default::default(),
+            identification: Default::default(),
+            electrical: Default::default(),
+            health: Default::default(),
         }
     }
 }
 
+/// Runtime overrides of the output channel settings.
+///
+/// They are runtime state and kept separate from the settings, which are replaced when changes
+/// are reverted, committed or loaded.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Override {
+    /// Setpoint override (connection-loss policy).
+    pub setpoint: Option<f32>,
+
+    /// Output current command of the outer loop in A. `None` in the `Temperature` mode.
+    pub command: Option<f64>,
+}
+
 impl OutputChannel {
+    /// Weighted iir input
+    pub fn input(&self, temperatures: &[[f64; 4]; 4]) -> f64 {
+        weighted_sum(temperatures, &self.weights)
+    }
+
     /// compute weighted iir input, iir state and return the new output
-    pub fn update(&mut self, temperatures: &[[f64; 4]; 4], iir_state: &mut [f64; 4]) -> f64 {
-        let temperature = temperatures
-            .as_flattened()
-            .iter()
-            .zip(self.weights.as_flattened().iter())
-            .map(|(t, w)| t * *w as f64)
-            .sum();
-        let iir = if *self.state == State::On {
-            &self.iir
+    ///
+    /// The output is held if `hold` is true (e.g. during open-loop identification) or the channel
+    /// is not `On`. The held output includes the last feed-forward output.
+    pub fn update(
+        &mut self,
+        temperatures: &[[f64; 4]; 4],
+        iir_state: &mut [f64; 4],
+        feedforward_state: &mut [f64; 4],
+        hold: bool,
+        overrides: &Override,
+    ) -> f64 {
+        let temperature = self.input(temperatures);
+        if *self.state != State::On || hold {
+            // Hold the total output: the loop output and the last feed-forward.
+            iir::Biquad::HOLD.update(iir_state, temperature) + feedforward_state[2]
+        } else if let Some(command) = overrides.command {
+            // Hold the loop at the outer loop command for a bumpless transfer.
+            iir_state[2] = command.max(self.iir.min()).min(self.iir.max());
+            iir::Biquad::HOLD.update(iir_state, temperature)
         } else {
-            &iir::Biquad::HOLD
-        };
-        iir.update(iir_state, temperature)
+            let feedforward = self.feedforward.update(temperatures, feedforward_state);
+            // Shift the loop output limits so that the sum with the feed-forward stays within the
+            // output limits and the integrator does not wind up.
+            let mut iir = self.iir;
+            if let Some(setpoint) = overrides.setpoint {
+                iir.set_input_offset(-setpoint as _);
+            }
+            iir.set_min(self.iir.min() - feedforward);
+            iir.set_max(self.iir.max() - feedforward);
+            iir.update(iir_state, temperature) + feedforward
+        }
+    }
+
+    /// Validate the output channel settings.
+    ///
+    /// # Args
+    /// * `enabled` - The enabled input channels.
+    pub fn validate(&self, enabled: &[[bool; 4]; 4]) -> validate::Result {
+        validate::non_negative(*self.voltage_limit, "voltage_limit: negative")?;
+        // A zero slew rate would freeze the output. `NaN` is unlimited.
+        if !self.slew_rate.is_nan() {
+            validate::positive(*self.slew_rate, "slew_rate: not positive")?;
+        }
+        self.pid.validate()?;
+        validate_weights(&self.weights, enabled)?;
+        self.feedforward.pid.validate()?;
+        validate_weights(&self.feedforward.weights, enabled)?;
+        self.identification.validate()?;
+        self.electrical.validate()?;
+        self.health.validate()
+    }
+
+    /// The effectively applied values after [OutputChannel::finalize_settings].
+    pub fn applied(&self) -> Applied {
+        let [min, max] = self.output_limits();
+        Applied {
+            voltage_limit: *self.voltage_limit,
+            output_limits: [min as _, max as _],
+            current_limits: self.current_limits(),
+            weights: *self.weights,
+        }
     }
 
     /// Performs finalization of the output_channel miniconf settings:
     /// - Clamping of the limits
     /// - Normalization of the weights
     ///
-    /// Returns the current limits.
-    pub fn finalize_settings(&mut self) {
+    /// Returns [Code::Pid] if the PID or feed-forward Biquad could not be built. The previous
+    /// Biquad is retained in that case.
+    pub fn finalize_settings(&mut self) -> Result<(), Code> {
+        let mut result = Ok(());
         if let Ok(iir) = self.pid.try_into() {
             self.iir = iir;
         } else {
             log::info!("Pid build failure, update not applied.");
+            result = Err(Code::Pid);
+        }
+        if let Ok(iir) = self.feedforward.pid.try_into() {
+            self.feedforward.iir = iir;
+        } else {
+            log::info!("Feed-forward Pid build failure, update not applied.");
+            result = Err(Code::Pid);
         }
         let range = DacCode::MAX_CURRENT.min(Pwm::MAX_CURRENT_LIMIT);
         self.iir
@@ -187,6 +384,8 @@ impl OutputChannel {
         self.iir
             .set_min(self.iir.min().clamp(-range as _, range as _));
         *self.voltage_limit = (*self.voltage_limit).clamp(0.0, Pwm::MAX_VOLTAGE_LIMIT);
+        *self.slew_rate = validate::unlimited(*self.slew_rate).max(0.0);
+        *self.electrical.power_limit = validate::unlimited(*self.electrical.power_limit).max(0.0);
         let divisor: f32 = self.weights.iter().flatten().map(|w| w.abs()).sum();
         // Note: The weights which are not 'None' should always affect an enabled channel and therefore count for normalization.
         if divisor != 0.0 {
@@ -195,6 +394,23 @@ impl OutputChannel {
                 *w *= n;
             }
         }
+        result
+    }
+
+    /// The effective setpoint including a runtime override.
+    pub fn setpoint(&self, overrides: &Override) -> f32 {
+        overrides.setpoint.unwrap_or(*self.pid.setpoint)
+    }
+
+    /// Limit the change of the output with respect to the previous output to the slew rate.
+    pub fn limit_slew(&self, output: f64, previous: f64) -> f64 {
+        let step = *self.slew_rate as f64 * SAMPLE_PERIOD;
+        output.max(previous - step).min(previous + step)
+    }
+
+    /// Output limits `[min, max]` of the loop.
+    pub fn output_limits(&self) -> [f64; 2] {
+        [self.iir.min(), self.iir.max()]
     }
 
     pub fn current_limits(&self) -> [f32; 2] {
@@ -207,3 +423,31 @@ impl OutputChannel {
         ]
     }
 }
+
+/// Effectively applied output channel settings.
+///
+/// These may differ from the requested values due to clamping and normalization.
+#[derive(Copy, Clone, Debug, Default, serde::Serialize)]
+pub struct Applied {
+    /// Voltage limit in V.
+    pub voltage_limit: f32,
+    /// Loop output limits `[min, max]` in A.
+    pub output_limits: [f32; 2],
+    /// PWM current limits `[positive, negative]` in A.
+    pub current_limits: [f32; 2],
+    /// Normalized input channel weights.
+    pub weights: [[f32; 4]; 4],
+}
+
+/// Feed back a downstream reduction of the output into the loop state (anti-windup).
+///
+/// The Biquad state holds the last output in `iir_state[2]`. Reducing it by the same amount as
+/// the output keeps the integrator from winding up.
+///
+/// # Args
+/// * `iir_state` - Biquad state of the loop.
+/// * `output` - Output as computed by [OutputChannel::update].
+/// * `limited` - Output after downstream limiting.
+pub fn anti_windup(iir_state: &mut [f64; 4], output: f64, limited: f64) {
+    iir_state[2] -= output - limited;
+}
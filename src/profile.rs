@@ -0,0 +1,215 @@
+//! # Thermostat_EEM settings profiles
+//!
+//! Named profiles (e.g. alignment, cooldown, bake-out) store a complete copy of the runtime
+//! settings in the flash key-value store next to the persisted settings. Each value is stored
+//! under `profile/<name>/<path>`. The profile names are kept in an index under `profiles` and the
+//! boot default profile under `profile`. None of these keys start with `/` and thus they never
+//! collide with a setting. The input channel configuration is derived from the hardware at boot
+//! and thus neither saved to nor loaded from profiles.
+//!
+//! Profiles are managed using the `profile_save`, `profile_load`, `profile_list`,
+//! `profile_delete` and `profile_default` commands from the USB terminal and over MQTT. The boot
+//! default profile is loaded after the persisted settings at boot.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+use miniconf::{postcard, Path, TreeDeserializeOwned, TreeKey, TreeSerialize};
+use serial_settings::Platform;
+
+use crate::command::Error;
+
+/// Maximum length of a profile name.
+pub const NAME_SIZE: usize = 16;
+
+/// Maximum number of profiles.
+pub const PROFILES: usize = 8;
+
+/// Profile name.
+pub type Name = String<NAME_SIZE>;
+
+/// Flash key of the profile index.
+const INDEX_KEY: &str = "profiles";
+
+/// Flash key of the boot default profile.
+const DEFAULT_KEY: &str = "profile";
+
+/// Paths that are neither saved to nor loaded from profiles.
+const EXCLUDED: &str = "/input/";
+
+/// Parse and check a profile name.
+///
+/// Names are non-empty and consist of ASCII alphanumeric characters, `-` and `_`.
+pub fn name(name: &str) -> Result<Name, Error> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::Argument);
+    }
+    name.try_into().map_err(|_| Error::Argument)
+}
+
+/// Flash key of a profile value.
+fn key(name: &str, path: &str) -> Option<String<128>> {
+    let mut key = String::new();
+    write!(key, "profile/{name}{path}").ok()?;
+    Some(key)
+}
+
+/// Read the profile index.
+pub fn list<P: Platform>(platform: &mut P) -> Result<Vec<Name, PROFILES>, Error> {
+    let mut buffer = [0u8; 256];
+    match platform
+        .fetch(&mut buffer, INDEX_KEY.as_bytes())
+        .map_err(|_| Error::Storage)?
+    {
+        Some(index) => ::postcard::from_bytes(index).map_err(|_| Error::Storage),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Write the profile index.
+fn store_list<P: Platform>(platform: &mut P, index: &Vec<Name, PROFILES>) -> Result<(), Error> {
+    let mut value = [0u8; 256];
+    let value = ::postcard::to_slice(index, &mut value).map_err(|_| Error::Storage)?;
+    let mut buffer = [0u8; 512];
+    platform
+        .store(&mut buffer, INDEX_KEY.as_bytes(), value)
+        .map_err(|_| Error::Storage)
+}
+
+/// Save the settings as a profile.
+///
+/// An existing profile of the same name is overwritten.
+pub fn save<P: Platform, T: TreeKey + TreeSerialize, const Y: usize>(
+    platform: &mut P,
+    name: &str,
+    settings: &T,
+) -> Result<(), Error> {
+    let mut index = list(platform)?;
+    if !index.iter().any(|n| n == name) {
+        index
+            .push(name.try_into().map_err(|_| Error::Argument)?)
+            .map_err(|_| Error::Storage)?;
+    }
+    let mut value_buffer = [0u8; 256];
+    let mut buffer = [0u8; 512];
+    for path in T::nodes::<Path<String<128>, '/'>, Y>() {
+        let Ok((path, _node)) = path else {
+            continue;
+        };
+        if path.as_str().starts_with(EXCLUDED) {
+            continue;
+        }
+        // Absent values (e.g. disabled input channels) are not stored.
+        let Ok(value) = postcard::get_by_key(
+            settings,
+            &path,
+            ::postcard::ser_flavors::Slice::new(&mut value_buffer),
+        ) else {
+            continue;
+        };
+        let key = key(name, path.as_str()).ok_or(Error::Argument)?;
+        platform
+            .store(&mut buffer, key.as_bytes(), value)
+            .map_err(|_| Error::Storage)?;
+    }
+    store_list(platform, &index)
+}
+
+/// Load a profile into the settings.
+///
+/// Values not contained in the profile are left unchanged. Values that fail to load (e.g. due to
+/// a changed settings layout) are skipped with a warning.
+pub fn load<P: Platform, T: TreeKey + TreeDeserializeOwned, const Y: usize>(
+    platform: &mut P,
+    name: &str,
+    settings: &mut T,
+) -> Result<(), Error> {
+    if !list(platform)?.iter().any(|n| n == name) {
+        return Err(Error::Argument);
+    }
+    let mut buffer = [0u8; 512];
+    for path in T::nodes::<Path<String<128>, '/'>, Y>() {
+        let Ok((path, _node)) = path else {
+            continue;
+        };
+        if path.as_str().starts_with(EXCLUDED) {
+            continue;
+        }
+        let key = key(name, path.as_str()).ok_or(Error::Argument)?;
+        let Some(value) = platform
+            .fetch(&mut buffer, key.as_bytes())
+            .map_err(|_| Error::Storage)?
+        else {
+            continue;
+        };
+        let flavor = ::postcard::de_flavors::Slice::new(value);
+        if let Err(e) = postcard::set_by_key(settings, &path, flavor) {
+            log::warn!("Failed to load `{}` from profile: {e:?}", path.as_str());
+        }
+    }
+    Ok(())
+}
+
+/// Delete a profile.
+///
+/// If it is the boot default profile, the boot default is cleared.
+pub fn delete<P: Platform, T: TreeKey, const Y: usize>(
+    platform: &mut P,
+    name: &str,
+) -> Result<(), Error> {
+    let mut index = list(platform)?;
+    let position = index
+        .iter()
+        .position(|n| n == name)
+        .ok_or(Error::Argument)?;
+    index.remove(position);
+    let mut buffer = [0u8; 512];
+    for path in T::nodes::<Path<String<128>, '/'>, Y>() {
+        let Ok((path, _node)) = path else {
+            continue;
+        };
+        let key = key(name, path.as_str()).ok_or(Error::Argument)?;
+        if platform
+            .fetch(&mut buffer, key.as_bytes())
+            .map_err(|_| Error::Storage)?
+            .is_some()
+        {
+            platform
+                .clear(&mut buffer, key.as_bytes())
+                .map_err(|_| Error::Storage)?;
+        }
+    }
+    if default(platform)?.is_some_and(|n| n == name) {
+        set_default(platform, None)?;
+    }
+    store_list(platform, &index)
+}
+
+/// The boot default profile.
+pub fn default<P: Platform>(platform: &mut P) -> Result<Option<Name>, Error> {
+    let mut buffer = [0u8; 512];
+    let value = platform
+        .fetch(&mut buffer, DEFAULT_KEY.as_bytes())
+        .map_err(|_| Error::Storage)?;
+    Ok(value
+        .and_then(|v| core::str::from_utf8(v).ok())
+        .and_then(|v| v.try_into().ok()))
+}
+
+/// Set or clear the boot default profile.
+pub fn set_default<P: Platform>(platform: &mut P, name: Option<&str>) -> Result<(), Error> {
+    let mut buffer = [0u8; 512];
+    match name {
+        Some(name) => {
+            if !list(platform)?.iter().any(|n| n == name) {
+                return Err(Error::Argument);
+            }
+            platform.store(&mut buffer, DEFAULT_KEY.as_bytes(), name.as_bytes())
+        }
+        None => platform.clear(&mut buffer, DEFAULT_KEY.as_bytes()),
+    }
+    .map_err(|_| Error::Storage)
+}
@@ -22,15 +22,26 @@
 //!    settings values
 //! 3. Unknown/unneeded settings values in flash can be actively ignored, facilitating simple flash
 //!    storage sharing.
-use crate::hardware::{flash::Flash, metadata::ApplicationMetadata, platform};
+use crate::{
+    command::{self, Command, Request},
+    config,
+    hardware::{
+        flash::Flash,
+        metadata::{self, ApplicationMetadata},
+        platform,
+    },
+    migration,
+};
 use core::fmt::Write;
 use embassy_futures::block_on;
 use embedded_io::Write as EioWrite;
 use heapless::{String, Vec};
-use miniconf::{postcard, Leaf, Path, Tree, TreeDeserializeOwned, TreeKey, TreeSerialize};
+use miniconf::{
+    postcard, Leaf, Path, Traversal, Tree, TreeDeserializeOwned, TreeKey, TreeSerialize,
+};
 use sequential_storage::{
     cache::NoCache,
-    map::{fetch_item, store_item, SerializationError},
+    map::{fetch_all_items, fetch_item, store_item, SerializationError},
 };
 use serial_settings::{BestEffortInterface, Platform, Settings};
 use smoltcp_nal::smoltcp::wire::EthernetAddress;
@@ -77,7 +88,7 @@ pub trait AppSettings {
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq)]
-pub struct SettingsKey(Vec<u8, 128>);
+pub struct SettingsKey(pub Vec<u8, 128>);
 
 impl sequential_storage::map::Key for SettingsKey {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
@@ -105,13 +116,70 @@ pub struct SerialSettingsPlatform<C, const Y: usize> {
 
     /// Metadata associated with the application
     pub metadata: &'static ApplicationMetadata,
+
+    /// Application command received from the user, to be executed by the application.
+    pub pending: Option<Request>,
+
+    /// Settings schema migration report from boot.
+    pub migration: migration::Report,
+
+    /// Configuration document buffer. It is lent to the MQTT client between polls of the USB
+    /// terminal (see [config]) and holds the document of a pending import.
+    pub import: Option<&'static mut config::Document>,
+
+    /// Configuration export in progress to the USB terminal.
+    pub export: Option<config::Export>,
+
+    /// A configuration document is being received as a raw line from the USB terminal.
+    pub raw: bool,
 }
 
 impl<C, const Y: usize> SerialSettingsPlatform<C, Y>
 where
     C: TreeDeserializeOwned + TreeSerialize + TreeKey,
 {
-    pub fn load(structure: &mut C, storage: &mut Flash) {
+    /// Receive a configuration document as a raw line from the USB terminal.
+    ///
+    /// The document is read directly into the document buffer, bypassing the terminal. Leading
+    /// line breaks are skipped. Once the line is complete, the import is pending.
+    pub fn receive(&mut self) {
+        let Some(document) = self.import.as_deref_mut() else {
+            self.raw = false;
+            return;
+        };
+        let mut buffer = [0u8; 64];
+        // `WouldBlock` once all received data has been read.
+        while let Ok(len) = self.interface.inner_mut().read(&mut buffer) {
+            for &byte in &buffer[..len] {
+                match byte {
+                    b'\r' | b'\n' if document.is_empty() => {}
+                    b'\r' | b'\n' => {
+                        self.raw = false;
+                        self.pending = Some(Request {
+                            command: Command::Import,
+                            argument: String::new(),
+                        });
+                        return;
+                    }
+                    byte => {
+                        if document.push(byte).is_err() {
+                            document.clear();
+                            self.raw = false;
+                            writeln!(
+                                &mut self.interface,
+                                "Command failed: {:?}",
+                                command::Error::Argument
+                            )
+                            .ok();
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn load(structure: &mut C, storage: &mut Flash, report: &mut migration::Report) {
         // Loop over flash and read settings
         let mut buffer = [0u8; 512];
         for path in C::nodes::<Path<String<128>, '/'>, Y>() {
@@ -142,16 +210,285 @@ where
             log::info!("Loading initial `{}` from flash", path.as_str());
 
             let flavor = ::postcard::de_flavors::Slice::new(value);
-            if let Err(e) = postcard::set_by_key(structure, &path, flavor) {
-                log::warn!(
-                    "Failed to deserialize `{}` from flash: {e:?}",
-                    path.as_str()
-                );
+            match postcard::set_by_key(structure, &path, flavor) {
+                // Intermediate states while loading may be rejected. The value is kept and the
+                // caller validates the result as a whole.
+                Ok(_) | Err(miniconf::Error::Traversal(Traversal::Invalid(..))) => {}
+                Err(e) => {
+                    log::warn!(
+                        "Failed to deserialize `{}` from flash: {e:?}",
+                        path.as_str()
+                    );
+                    report.push(migration::Outcome::Dropped, path.as_str());
+                }
             }
         }
     }
 }
 
+impl<C, const Y: usize> SerialSettingsPlatform<C, Y>
+where
+    C: Settings,
+{
+    /// Compare the values of a settings subtree with their persisted values and optionally store
+    /// the differing values.
+    ///
+    /// Values that are not persisted compare against their defaults.
+    ///
+    /// # Args
+    /// * `prefix` - The path of the subtree in the settings, e.g. `/thermostat_eem`.
+    /// * `live` - The live subtree.
+    /// * `default` - The subtree defaults.
+    /// * `save` - Store the differing values.
+    /// * `differs` - Called with the path (relative to `prefix`) of each differing value.
+    ///
+    /// # Returns
+    /// The number of differing values.
+    pub fn persist<T: TreeKey + TreeSerialize, const D: usize>(
+        &mut self,
+        prefix: &str,
+        live: &T,
+        default: &T,
+        save: bool,
+        mut differs: impl FnMut(&str),
+    ) -> Result<usize, <Self as Platform>::Error> {
+        let mut live_buffer = [0u8; 256];
+        let mut default_buffer = [0u8; 256];
+        let mut buffer = [0u8; 512];
+        let mut count = 0;
+        for path in T::nodes::<Path<String<128>, '/'>, D>() {
+            let Ok((path, _node)) = path else {
+                continue;
+            };
+            // Absent values (e.g. disabled input channels) are not persisted.
+            let Ok(value) = postcard::get_by_key(
+                live,
+                &path,
+                ::postcard::ser_flavors::Slice::new(&mut live_buffer),
+            ) else {
+                continue;
+            };
+            let mut key: String<128> = String::new();
+            if key
+                .push_str(prefix)
+                .and_then(|_| key.push_str(path.as_str()))
+                .is_err()
+            {
+                continue;
+            }
+            let same = match self.fetch(&mut buffer, key.as_bytes())? {
+                Some(persisted) => persisted == &value[..],
+                None => postcard::get_by_key(
+                    default,
+                    &path,
+                    ::postcard::ser_flavors::Slice::new(&mut default_buffer),
+                )
+                .is_ok_and(|default| default == value),
+            };
+            if same {
+                continue;
+            }
+            differs(path.as_str());
+            count += 1;
+            if save {
+                log::info!("Saving `{}` to flash", key.as_str());
+                self.store(&mut buffer, key.as_bytes(), value)?;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Flash key of the boot counter. It does not start with `/` and never collides with a setting.
+const BOOT_COUNT_KEY: &[u8] = b"boot_count";
+
+/// Increment the persistent boot counter.
+///
+/// # Returns
+/// The number of boots including the current one.
+pub fn count_boot(storage: &mut Flash) -> u32 {
+    let range = storage.range();
+    let key = SettingsKey(Vec::try_from(BOOT_COUNT_KEY).unwrap());
+    let mut buffer = [0u8; 64];
+    let value: Result<Option<&[u8]>, _> = block_on(fetch_item(
+        storage,
+        range.clone(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &key,
+    ));
+    let count = match value {
+        Ok(Some(value)) => value.try_into().map_or(0, u32::from_le_bytes),
+        Ok(None) => 0,
+        Err(e) => {
+            log::warn!("Failed to fetch boot count from flash: {e:?}");
+            0
+        }
+    }
+    .wrapping_add(1);
+    if let Err(e) = block_on(store_item(
+        storage,
+        range,
+        &mut NoCache::new(),
+        &mut buffer,
+        &key,
+        &&count.to_le_bytes()[..],
+    )) {
+        log::warn!("Failed to store boot count to flash: {e:?}");
+    }
+    count
+}
+
+/// Flash key of the flash layout version.
+const LAYOUT_KEY: &[u8] = b"layout";
+
+/// Flash layout version with the event log region at the end of the bank.
+const LAYOUT: u8 = 1;
+
+/// Append a length prefixed entry to a flat buffer.
+fn push_entry<const N: usize>(buffer: &mut Vec<u8, N>, data: &[u8]) -> bool {
+    let Ok(len) = u16::try_from(data.len()) else {
+        return false;
+    };
+    buffer.extend_from_slice(&len.to_le_bytes()).is_ok() && buffer.extend_from_slice(data).is_ok()
+}
+
+/// Iterate over the entries of a flat buffer filled by [push_entry].
+fn entries(mut buffer: &[u8]) -> impl Iterator<Item = &[u8]> {
+    core::iter::from_fn(move || {
+        let (len, rest) = buffer.split_first_chunk::<2>()?;
+        let (entry, rest) = rest.split_at_checked(u16::from_le_bytes(*len) as usize)?;
+        buffer = rest;
+        Some(entry)
+    })
+}
+
+/// Move the persisted items out of the event log region.
+///
+/// Earlier firmware used the entire flash bank for the settings. Shrinking the settings range in
+/// place would orphan the items in the event log region and leave the settings map inconsistent.
+/// Instead, on the first boot with the event log, the current items are read from the entire bank
+/// into RAM, the bank is erased and the items are stored again in the settings range. Items that
+/// do not fit into the buffers are dropped and logged.
+///
+/// This must be called before the settings are migrated and loaded.
+pub fn upgrade_layout(storage: &mut Flash) {
+    let range = storage.range();
+    let bank = range.start..storage.log_range().end;
+    let layout_key = SettingsKey(Vec::try_from(LAYOUT_KEY).unwrap());
+    let mut buffer = [0u8; 512];
+    let mut value = [0u8; 256];
+
+    let layout: Result<Option<&[u8]>, _> = block_on(fetch_item(
+        storage,
+        range.clone(),
+        &mut NoCache::new(),
+        &mut buffer,
+        &layout_key,
+    ));
+    if matches!(layout, Ok(Some([LAYOUT]))) {
+        return;
+    }
+    log::info!("Upgrading flash layout to {LAYOUT}");
+
+    // Collect the keys. The bank may contain stale items for the same key.
+    let mut keys: Vec<u8, 4096> = Vec::new();
+    let mut erase = false;
+    let mut cache = NoCache::new();
+    match block_on(fetch_all_items::<SettingsKey, _, _>(
+        storage,
+        bank.clone(),
+        &mut cache,
+        &mut buffer,
+    )) {
+        Ok(mut items) => loop {
+            match block_on(items.next::<SettingsKey, &[u8]>(&mut value)) {
+                Ok(Some((key, _item))) => {
+                    erase = true;
+                    if !entries(&keys).any(|k| k == &key.0[..]) && !push_entry(&mut keys, &key.0) {
+                        log::warn!(
+                            "Dropping `{}` on flash layout upgrade",
+                            core::str::from_utf8(&key.0).unwrap_or("?")
+                        );
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("Failed to read flash items: {e:?}");
+                    erase = true;
+                    break;
+                }
+            }
+        },
+        Err(e) => {
+            log::warn!("Failed to read flash items: {e:?}");
+            erase = true;
+        }
+    }
+
+    // Read the current values.
+    let mut items: Vec<u8, 16384> = Vec::new();
+    for key in entries(&keys) {
+        let key = SettingsKey(Vec::from_slice(key).unwrap());
+        let item: Result<Option<&[u8]>, _> = block_on(fetch_item(
+            storage,
+            bank.clone(),
+            &mut NoCache::new(),
+            &mut buffer,
+            &key,
+        ));
+        match item {
+            Ok(Some(item)) if !item.is_empty() => {
+                // Keep the key and value pairs intact.
+                if items.capacity() - items.len() < 4 + key.0.len() + item.len()
+                    || !(push_entry(&mut items, &key.0) && push_entry(&mut items, item))
+                {
+                    log::warn!(
+                        "Dropping `{}` on flash layout upgrade",
+                        core::str::from_utf8(&key.0).unwrap_or("?")
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if erase {
+        if let Err(e) = block_on(sequential_storage::erase_all(storage, bank)) {
+            log::error!("Failed to erase flash: {e:?}");
+            return;
+        }
+    }
+
+    let mut entries = entries(&items);
+    while let (Some(key), Some(item)) = (entries.next(), entries.next()) {
+        let key = SettingsKey(Vec::from_slice(key).unwrap());
+        if let Err(e) = block_on(store_item(
+            storage,
+            range.clone(),
+            &mut NoCache::new(),
+            &mut buffer,
+            &key,
+            &item,
+        )) {
+            log::warn!(
+                "Failed to store `{}` on flash layout upgrade: {e:?}",
+                core::str::from_utf8(&key.0).unwrap_or("?")
+            );
+        }
+    }
+    if let Err(e) = block_on(store_item(
+        storage,
+        range,
+        &mut NoCache::new(),
+        &mut buffer,
+        &layout_key,
+        &&[LAYOUT][..],
+    )) {
+        log::warn!("Failed to store flash layout: {e:?}");
+    }
+}
+
 impl<C, const Y: usize> Platform for SerialSettingsPlatform<C, Y>
 where
     C: Settings,
@@ -229,14 +566,63 @@ where
                     "Panic Info", self.metadata.panic_info
                 )
                 .unwrap();
-            }
-            _ => {
                 writeln!(
-                    self.interface_mut(),
-                    "Invalid platform command: `{cmd}` not in [`dfu`, `reboot`, `service`]"
+                    &mut self.interface,
+                    "{:<20}: {:?}",
+                    "Reset Cause", self.metadata.reset_cause
+                )
+                .unwrap();
+                writeln!(
+                    &mut self.interface,
+                    "{:<20}: {}",
+                    "Boot Count", self.metadata.boot_count
                 )
-                .ok();
+                .unwrap();
+                writeln!(
+                    &mut self.interface,
+                    "{:<20}: {} s",
+                    "Uptime",
+                    metadata::uptime()
+                )
+                .unwrap();
+                writeln!(
+                    &mut self.interface,
+                    "{:<20}: {}",
+                    "Settings Schema", self.migration
+                )
+                .unwrap();
             }
+            "import" => match self.import.as_deref() {
+                // The document does not fit into the terminal input buffer and its values may
+                // contain whitespace. It is received as a raw line, see [Self::receive].
+                Some(document) if document.is_empty() => {
+                    self.raw = true;
+                    writeln!(self.interface_mut(), "Send the document on one line").ok();
+                }
+                _ => {
+                    writeln!(
+                        self.interface_mut(),
+                        "Command failed: {:?}",
+                        command::Error::Busy
+                    )
+                    .ok();
+                }
+            },
+            cmd => match cmd.parse() {
+                Ok(request) => self.pending = Some(request),
+                Err(e) => {
+                    write!(
+                        self.interface_mut(),
+                        "Invalid platform command: `{cmd}` ({e:?}) not in [`dfu`, `reboot`, `service`"
+                    )
+                    .ok();
+                    for command in <Command as strum::IntoEnumIterator>::iter() {
+                        let name: &str = command.into();
+                        write!(self.interface_mut(), ", `{name}`").ok();
+                    }
+                    writeln!(self.interface_mut(), "]").ok();
+                }
+            },
         }
     }
 
@@ -0,0 +1,215 @@
+//! # Thermostat_EEM supply supervision
+//!
+//! The supply rails are checked against configurable limits. The 12 V input power available to
+//! the TEC outputs depends on the supply (802.3af or 802.3at Power over Ethernet or an external
+//! supply). The total output current is limited to that budget by allocating it to the output
+//! channels in priority order. On supply rail under-voltage the output channels are shed
+//! (turned off) one by one in reverse priority order and restored once the rails have recovered.
+
+use miniconf::{Leaf, Tree};
+use serde::Serialize;
+
+use crate::{hardware::gpio::PoePower, interlock::Constraint, output_channel::State, validate};
+
+/// Supply supervision settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Supply {
+    /// 3.3 V rail limits `[min, max]` in V.
+    pub p3v3: Leaf<[f32; 2]>,
+
+    /// 5 V rail limits `[min, max]` in V.
+    pub p5v: Leaf<[f32; 2]>,
+
+    /// 12 V rail limits `[min, max]` in V.
+    pub p12v: Leaf<[f32; 2]>,
+
+    /// 12 V power budget for the TEC outputs in W.
+    ///
+    /// # Value
+    /// `[<external supply>, <802.3af PoE>, <802.3at PoE>]`, `inf` or `NaN` (`null` in
+    /// JSON) to disable.
+    pub budget: Leaf<[f32; 3]>,
+
+    /// Priority of each output channel.
+    ///
+    /// The power budget is allocated to the channels with higher priority first and channels with
+    /// lower priority are shed first. Channels of equal priority are shed in reverse index order.
+    pub priority: Leaf<[u8; 4]>,
+
+    /// Time in seconds the rails need to be within their limits before a shed output channel is
+    /// restored.
+    pub restore: Leaf<f32>,
+}
+
+impl Default for Supply {
+    fn default() -> Self {
+        Self {
+            p3v3: [3.1, 3.5].into(),
+            p5v: [4.7, 5.3].into(),
+            p12v: [10.8, 13.2].into(),
+            budget: [f32::INFINITY, 11.0, 22.0].into(),
+            priority: [0; 4].into(),
+            restore: 10.0.into(),
+        }
+    }
+}
+
+impl Supply {
+    /// Validate the supply supervision settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::range(*self.p3v3, "supply/p3v3: min > max")?;
+        validate::range(*self.p5v, "supply/p5v: min > max")?;
+        validate::range(*self.p12v, "supply/p12v: min > max")?;
+        self.budget
+            .iter()
+            .try_for_each(|b| validate::limit(*b, "supply/budget: negative"))?;
+        validate::non_negative(*self.restore, "supply/restore: negative")
+    }
+
+    /// The power budget for the TEC outputs in W.
+    pub fn budget(&self, poe: PoePower) -> f32 {
+        validate::unlimited(match poe {
+            PoePower::Absent => self.budget[0],
+            PoePower::Low => self.budget[1],
+            PoePower::High => self.budget[2],
+        })
+    }
+
+    /// Output channel indices by decreasing priority.
+    fn order(&self) -> [usize; 4] {
+        let mut order = [0, 1, 2, 3];
+        order.sort_unstable_by_key(|ch| (core::cmp::Reverse(self.priority[*ch]), *ch));
+        order
+    }
+}
+
+/// Supply supervision telemetry.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct SupplyStatus {
+    /// 12 V input power in W.
+    pub power: f32,
+    /// Power budget for the TEC outputs in W.
+    pub budget: f32,
+    /// A supply rail is below its minimum.
+    pub undervoltage: bool,
+    /// A supply rail is above its maximum.
+    pub overvoltage: bool,
+    /// Output channels shed due to under-voltage.
+    pub shed: [bool; 4],
+}
+
+/// Supply rail voltages and output currents.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Rails {
+    /// 3.3 V rail voltage.
+    pub p3v3: f32,
+    /// 5 V rail voltage.
+    pub p5v: f32,
+    /// 12 V rail voltage.
+    pub p12v: f32,
+    /// 12 V rail current in A.
+    pub p12v_current: f32,
+    /// See [PoePower]
+    pub poe: PoePower,
+    /// Output channel currents in A.
+    pub output_current: [f32; 4],
+}
+
+/// Runtime state of the supply supervision.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Supervisor {
+    undervoltage: bool,
+    overvoltage: bool,
+    shed: [bool; 4],
+    recovered: f32,
+}
+
+impl Supervisor {
+    /// Update the supply supervision.
+    ///
+    /// # Args
+    /// * `settings` - Supply supervision settings.
+    /// * `rails` - Measured supply rails and output currents.
+    /// * `enabled` - Output channels configured to be not off.
+    /// * `period` - Update period in seconds.
+    ///
+    /// # Returns
+    /// The output channel constraints and the supply telemetry.
+    pub fn update(
+        &mut self,
+        settings: &Supply,
+        rails: &Rails,
+        enabled: [bool; 4],
+        period: f32,
+    ) -> ([Constraint; 4], SupplyStatus) {
+        let voltages = [
+            (rails.p3v3, *settings.p3v3),
+            (rails.p5v, *settings.p5v),
+            (rails.p12v, *settings.p12v),
+        ];
+        let undervoltage = voltages.iter().any(|(v, [min, _])| *v < *min);
+        let overvoltage = voltages.iter().any(|(v, [_, max])| *v > *max);
+        if undervoltage && !self.undervoltage {
+            log::error!(
+                "Supply under-voltage: 3.3 V: {} V, 5 V: {} V, 12 V: {} V",
+                rails.p3v3,
+                rails.p5v,
+                rails.p12v
+            );
+        }
+        if overvoltage && !self.overvoltage {
+            log::error!(
+                "Supply over-voltage: 3.3 V: {} V, 5 V: {} V, 12 V: {} V",
+                rails.p3v3,
+                rails.p5v,
+                rails.p12v
+            );
+        }
+        self.undervoltage = undervoltage;
+        self.overvoltage = overvoltage;
+
+        let order = settings.order();
+        if undervoltage {
+            self.recovered = 0.0;
+            // Shed one more channel per update while the under-voltage persists.
+            if let Some(ch) = order
+                .iter()
+                .rev()
+                .find(|ch| enabled[**ch] && !self.shed[**ch])
+            {
+                log::warn!("Shedding output channel {ch}");
+                self.shed[*ch] = true;
+            }
+        } else if self.shed.iter().any(|s| *s) {
+            self.recovered += period;
+            if self.recovered >= *settings.restore {
+                self.recovered = 0.0;
+                if let Some(ch) = order.iter().find(|ch| self.shed[**ch]) {
+                    log::info!("Restoring output channel {ch}");
+                    self.shed[*ch] = false;
+                }
+            }
+        }
+
+        // The 12 V input current drawn by an output is at most its output current.
+        let budget = settings.budget(rails.poe);
+        let mut available = budget / rails.p12v.max(1.0);
+        let mut constraints = [Constraint::default(); 4];
+        for ch in order {
+            constraints[ch] = Constraint {
+                state: self.shed[ch].then_some(State::Off),
+                current_limit: available.max(0.0),
+            };
+            available -= rails.output_current[ch].abs();
+        }
+
+        let status = SupplyStatus {
+            power: rails.p12v * rails.p12v_current,
+            budget,
+            undervoltage,
+            overvoltage,
+            shed: self.shed,
+        };
+        (constraints, status)
+    }
+}
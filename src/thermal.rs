@@ -0,0 +1,200 @@
+//! # Thermostat_EEM board thermal management
+//!
+//! The board temperature is measured by the LM75 sensor. Above the derating temperature the output
+//! current limits are reduced linearly to zero at the shutdown temperature. At the shutdown
+//! temperature, or when the hardware overtemperature signal is asserted, all outputs are turned
+//! off by the `Overtemperature` output channel fault (see [crate::fault]). It is latched until
+//! cleared using the `reset_fault` command and raised again if the condition persists.
+//!
+//! The fan is driven at a fixed duty cycle or ramped with the board or a heat-sink temperature.
+//! Its speed is measured using the tacho signal to detect a stalled fan.
+
+use miniconf::{Leaf, Tree};
+use serde::{Deserialize, Serialize};
+
+use crate::validate;
+
+/// Board thermal protection settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Thermal {
+    /// Board temperature above which the output current limits are derated (°C).
+    pub derate: Leaf<f32>,
+
+    /// Board temperature at which the outputs are shut down (°C).
+    pub shutdown: Leaf<f32>,
+
+    /// Shut down the outputs when the hardware overtemperature signal is asserted.
+    pub signal: Leaf<bool>,
+
+    /// Fan control settings.
+    pub fan: Fan,
+}
+
+impl Default for Thermal {
+    fn default() -> Self {
+        Self {
+            derate: 60.0.into(),
+            shutdown: 80.0.into(),
+            signal: true.into(),
+            fan: Default::default(),
+        }
+    }
+}
+
+impl Thermal {
+    /// Validate the thermal protection settings.
+    pub fn validate(&self) -> validate::Result {
+        validate::range([*self.derate, *self.shutdown], "thermal: derate > shutdown")?;
+        let fan = &self.fan;
+        if !(0.0..=1.0).contains(&*fan.duty) {
+            return Err("thermal/fan/duty: outside [0, 1]");
+        }
+        validate::range(*fan.ramp, "thermal/fan/ramp: min > max")?;
+        validate::non_negative(*fan.stall, "thermal/fan/stall: negative")
+    }
+
+    /// Output current limit scale factor.
+    ///
+    /// # Args
+    /// * `temperature` - Board temperature in °C. `NaN` if unavailable.
+    ///
+    /// # Returns
+    /// The factor in `[0, 1]` to apply to the output current limits.
+    pub fn derating(&self, temperature: f32) -> f32 {
+        if temperature.is_nan() {
+            return 1.0;
+        }
+        ((*self.shutdown - temperature) / (*self.shutdown - *self.derate))
+            .max(0.0)
+            .min(1.0)
+    }
+
+    /// Whether the outputs need to be shut down.
+    ///
+    /// # Args
+    /// * `temperature` - Board temperature in °C. `NaN` if unavailable.
+    /// * `signal` - Hardware overtemperature signal.
+    pub fn overtemperature(&self, temperature: f32, signal: bool) -> bool {
+        temperature >= *self.shutdown || (*self.signal && signal)
+    }
+}
+
+/// Fan control mode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FanMode {
+    /// Fan off.
+    #[default]
+    Off,
+    /// Fixed duty cycle.
+    Fixed,
+    /// Duty cycle ramped with temperature.
+    Temperature,
+}
+
+/// Fan control settings.
+#[derive(Copy, Clone, Debug, Tree)]
+pub struct Fan {
+    /// Fan control mode.
+    pub mode: Leaf<FanMode>,
+
+    /// Duty cycle in `Fixed` mode and minimum duty cycle in `Temperature` mode.
+    ///
+    /// # Value
+    /// `[0, 1]`
+    pub duty: Leaf<f32>,
+
+    /// Temperature input for the `Temperature` mode.
+    ///
+    /// # Value
+    /// `[<adc>, <channel>]` of a heat-sink input or `None` for the LM75 board temperature.
+    pub input: Leaf<Option<[u8; 2]>>,
+
+    /// Temperatures (°C) between which the duty cycle is ramped from `duty` to full.
+    pub ramp: Leaf<[f32; 2]>,
+
+    /// Fan speed (RPM) below which the fan is considered stalled. `0` to disable.
+    pub stall: Leaf<f32>,
+}
+
+impl Default for Fan {
+    fn default() -> Self {
+        Self {
+            mode: FanMode::Off.into(),
+            duty: 0.5.into(),
+            input: None.into(),
+            ramp: [30.0, 50.0].into(),
+            stall: 300.0.into(),
+        }
+    }
+}
+
+/// Fan telemetry.
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct FanStatus {
+    /// Fan speed in RPM.
+    pub rpm: f32,
+    /// Duty cycle.
+    pub duty: f32,
+    /// The fan is stalled.
+    pub stall: bool,
+}
+
+/// Runtime state of the fan controller.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FanController {
+    stall_time: f32,
+    stall: bool,
+}
+
+impl FanController {
+    /// Minimum duty cycle at which the fan is expected to spin.
+    const MIN_DUTY: f32 = 0.2;
+    /// Spin-up time before a stall is detected in seconds.
+    const SPIN_UP: f32 = 3.0;
+
+    /// Update the fan controller.
+    ///
+    /// # Args
+    /// * `settings` - Fan control settings.
+    /// * `temperature` - Temperature of the configured input in °C. `NaN` if unavailable.
+    /// * `rpm` - Measured fan speed.
+    /// * `period` - Update period in seconds.
+    ///
+    /// # Returns
+    /// The fan telemetry including the duty cycle to apply.
+    pub fn update(&mut self, settings: &Fan, temperature: f32, rpm: f32, period: f32) -> FanStatus {
+        let duty = match *settings.mode {
+            FanMode::Off => 0.0,
+            FanMode::Fixed => *settings.duty,
+            FanMode::Temperature => {
+                let [t0, t1] = *settings.ramp;
+                // Full speed if the temperature is unavailable.
+                let x = if temperature.is_nan() {
+                    1.0
+                } else {
+                    ((temperature - t0) / (t1 - t0)).max(0.0).min(1.0)
+                };
+                *settings.duty + (1.0 - *settings.duty) * x
+            }
+        }
+        .max(0.0)
+        .min(1.0);
+
+        if duty >= Self::MIN_DUTY && rpm < *settings.stall {
+            self.stall_time += period;
+        } else {
+            self.stall_time = 0.0;
+        }
+        let stall = self.stall_time > Self::SPIN_UP;
+        if stall != self.stall {
+            if stall {
+                log::error!("Fan stall at duty cycle {duty}");
+            } else {
+                log::info!("Fan running");
+            }
+            self.stall = stall;
+        }
+
+        FanStatus { rpm, duty, stall }
+    }
+}
@@ -0,0 +1,116 @@
+//! # Thermostat_EEM settings transactions
+//!
+//! Changing several settings (e.g. setpoint, gains and limits of a channel) one by one applies
+//! partially updated settings to the live loop. A transaction stages the changes instead: after
+//! the `begin` command, settings writes over MQTT and the USB terminal go to a staged copy of the
+//! settings. The `diff` command lists the staged changes, `commit` validates and applies them
+//! atomically and `abort` discards them.
+//!
+//! Rejected writes during a transaction are kept in the staged copy. The commit is refused until
+//! they are corrected or the transaction is aborted.
+
+use core::fmt::Write;
+use heapless::String;
+use miniconf::{json, Path, TreeKey, TreeSerialize};
+
+use crate::command::{Error, Response};
+
+/// Maximum serialized size of a value in the diff.
+const VALUE_SIZE: usize = 128;
+
+/// Space reserved in the response for the number of omitted changes.
+const MORE_SIZE: usize = 16;
+
+/// Staged settings.
+#[derive(Clone, Debug)]
+pub struct Transaction<T> {
+    staged: Option<T>,
+}
+
+impl<T> Default for Transaction<T> {
+    fn default() -> Self {
+        Self { staged: None }
+    }
+}
+
+impl<T: Clone> Transaction<T> {
+    /// Begin a transaction by staging a copy of the active settings.
+    pub fn begin(&mut self, active: &T) -> Result<(), Error> {
+        if self.staged.is_some() {
+            return Err(Error::Transaction);
+        }
+        self.staged = Some(active.clone());
+        Ok(())
+    }
+
+    /// Discard the staged settings.
+    pub fn abort(&mut self) -> Result<(), Error> {
+        self.staged.take().map(|_| ()).ok_or(Error::Transaction)
+    }
+
+    /// The staged settings if a transaction is in progress.
+    pub fn staged(&self) -> Option<&T> {
+        self.staged.as_ref()
+    }
+
+    /// The staged settings if a transaction is in progress.
+    pub fn staged_mut(&mut self) -> Option<&mut T> {
+        self.staged.as_mut()
+    }
+
+    /// End the transaction and take the staged settings.
+    pub fn take(&mut self) -> Result<T, Error> {
+        self.staged.take().ok_or(Error::Transaction)
+    }
+}
+
+/// Write the settings that differ between the active and the staged settings.
+///
+/// Each line has the form `<path>: <active> -> <staged>`. Values that do not serialize are
+/// written as `?`. Changes that do not fit into the response are counted in a final
+/// `… <N> more` line.
+pub fn diff<T: TreeKey + TreeSerialize, const Y: usize>(
+    active: &T,
+    staged: &T,
+    response: &mut Response,
+) -> Result<(), Error> {
+    let mut a = [0u8; VALUE_SIZE];
+    let mut s = [0u8; VALUE_SIZE];
+    let mut changes = 0;
+    let mut more = 0;
+    for path in T::nodes::<Path<String<128>, '/'>, Y>() {
+        let Ok((path, _node)) = path else {
+            continue;
+        };
+        let active = json::get_by_key(active, &path, &mut a).map(|len| &a[..len]);
+        let staged = json::get_by_key(staged, &path, &mut s).map(|len| &s[..len]);
+        let (active, staged) = match (active, staged) {
+            (Ok(active), Ok(staged)) if active == staged => continue,
+            (Err(_), Err(_)) => continue,
+            (active, staged) => (active.unwrap_or(b"?"), staged.unwrap_or(b"?")),
+        };
+        if more == 0 {
+            let len = response.len();
+            let written = writeln!(
+                response,
+                "{}: {} -> {}",
+                path.as_str(),
+                core::str::from_utf8(active).unwrap_or("?"),
+                core::str::from_utf8(staged).unwrap_or("?"),
+            )
+            .is_ok();
+            if written && response.len() + MORE_SIZE <= response.capacity() {
+                changes += 1;
+                continue;
+            }
+            response.truncate(len);
+        }
+        more += 1;
+    }
+    if more > 0 {
+        write!(response, "… {more} more")?;
+    } else if changes == 0 {
+        write!(response, "No changes")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! # Thermostat_EEM settings validation
+//!
+//! Settings changes (over MQTT or the USB terminal) are validated before they are applied.
+//! Invalid changes are rejected with an error response and the previously applied settings are
+//! restored. The helpers here check individual values and return the given error message.
+//!
+//! Non-finite values are exported as `null` and imported as `NaN` (see [crate::config]). Limits
+//! that default to infinity therefore accept `NaN` as unlimited.
+
+/// Validation result with the reason for a rejection.
+pub type Result = core::result::Result<(), &'static str>;
+
+/// Check that a value is positive and not `NaN`.
+pub fn positive(value: f32, error: &'static str) -> Result {
+    if value > 0.0 {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Check that a value is non-negative and not `NaN`.
+pub fn non_negative(value: f32, error: &'static str) -> Result {
+    if value >= 0.0 {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Check that a value is finite.
+pub fn finite(value: f32, error: &'static str) -> Result {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Check that a limit is non-negative. `NaN` is accepted as unlimited.
+pub fn limit(value: f32, error: &'static str) -> Result {
+    if value.is_nan() || value >= 0.0 {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// The value of a limit with `NaN` mapped to unlimited.
+pub fn unlimited(value: f32) -> f32 {
+    if value.is_nan() {
+        f32::INFINITY
+    } else {
+        value
+    }
+}
+
+/// Check that a range `[min, max]` is ordered. A `NaN` limit is accepted as unlimited.
+pub fn range([min, max]: [f32; 2], error: &'static str) -> Result {
+    if min.is_nan() || max.is_nan() || min <= max {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}